@@ -0,0 +1,265 @@
+use std::{collections::BTreeMap, fs, io, path::PathBuf, time::Duration};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{log, subsonic, Item, LogLevel, SlibError};
+
+/// A saved place in a long track, like an audiobook or a mix
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct Bookmark {
+    pub item: Item,
+    pub position: Duration,
+    pub comment: Option<String>,
+}
+
+impl Bookmark {
+    /// Read an entry of `getBookmarks`
+    pub fn from_subsonic(value: &Value) -> Option<Bookmark>
+    {
+        let entry = &value["entry"];
+        Some(Bookmark {
+            item: Item {
+                name: entry["title"].as_str().unwrap_or_default().to_string(),
+                id: entry["id"].as_str()?.to_string(),
+                image_path: entry["coverArt"].as_str().unwrap_or_default().to_string(),
+            },
+            position: Duration::from_millis(value["position"].as_u64()?),
+            comment: value["comment"].as_str().map(String::from),
+        })
+    }
+}
+
+pub fn fetch(server: &subsonic::Server) -> Result<Vec<Bookmark>, SlibError>
+{
+    let response = server.call("getBookmarks", &[])?;
+    let bookmarks = response["bookmarks"]["bookmark"].as_array().cloned().unwrap_or_default();
+    Ok(bookmarks.iter().filter_map(Bookmark::from_subsonic).collect())
+}
+
+pub fn create(server: &subsonic::Server, bookmark: &Bookmark) -> Result<(), SlibError>
+{
+    let position = bookmark.position.as_millis().to_string();
+    let mut params = vec![("id", bookmark.item.id.as_str()), ("position", &position)];
+    if let Some(comment) = &bookmark.comment {
+        params.push(("comment", comment));
+    }
+    server.call("createBookmark", &params).map(|_| ())
+}
+
+pub fn delete(server: &subsonic::Server, id: &str) -> Result<(), SlibError>
+{
+    server.call("deleteBookmark", &[("id", id)]).map(|_| ())
+}
+
+/// A change made while the server couldn't be reached
+#[derive(Deserialize, Serialize, Debug, Clone)]
+enum Change {
+    Set(Bookmark),
+    Delete(String),
+}
+
+impl Change {
+    fn id(&self) -> &str
+    {
+        match self {
+            Change::Set(bookmark) => &bookmark.item.id,
+            Change::Delete(id) => id,
+        }
+    }
+}
+
+/// Whether a server error is about the server or the account, rather than the change that was sent.
+/// Those are worth sending again once they are fixed, the rest would only fail again.
+fn retry_after(code: u32) -> bool
+{
+    // Versions that don't match, and logins that aren't accepted
+    (20..50).contains(&code)
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct State {
+    auto_resume: bool,
+    /// By item id, Subsonic keeps one bookmark per item
+    bookmarks: BTreeMap<String, Bookmark>,
+    pending: Vec<Change>,
+}
+
+/// Bookmarks kept on disk, with changes queued until they reach the server
+pub struct BookmarkStore {
+    path: PathBuf,
+    state: State,
+}
+
+impl BookmarkStore {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<BookmarkStore>
+    {
+        let path = path.into();
+        let state = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => State::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(BookmarkStore{path, state})
+    }
+
+    fn save(&self) -> io::Result<()>
+    {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string(&self.state)?)?;
+        fs::rename(tmp, &self.path)
+    }
+
+    pub fn list(&self) -> Vec<Bookmark>
+    {
+        self.state.bookmarks.values().cloned().collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Bookmark>
+    {
+        self.state.bookmarks.get(id)
+    }
+
+    /// Queue a change, only the latest for an item has to reach the server
+    fn queue(&mut self, change: Change)
+    {
+        self.state.pending.retain(|c| c.id() != change.id());
+        self.state.pending.push(change);
+    }
+
+    /// Add or replace the bookmark of an item
+    pub fn set(&mut self, bookmark: Bookmark) -> io::Result<()>
+    {
+        self.state.bookmarks.insert(bookmark.item.id.clone(), bookmark.clone());
+        self.queue(Change::Set(bookmark));
+        self.save()
+    }
+
+    /// Returns false if the item had no bookmark
+    pub fn delete(&mut self, id: &str) -> io::Result<bool>
+    {
+        if self.state.bookmarks.remove(id).is_none() {
+            return Ok(false);
+        }
+        self.queue(Change::Delete(id.to_string()));
+        self.save()?;
+        Ok(true)
+    }
+
+    pub fn auto_resume(&self) -> bool
+    {
+        self.state.auto_resume
+    }
+
+    /// Whether queueing a bookmarked item starts it from the bookmark
+    pub fn set_auto_resume(&mut self, auto_resume: bool) -> io::Result<()>
+    {
+        self.state.auto_resume = auto_resume;
+        self.save()
+    }
+
+    /// Where to start an item that was just queued
+    pub fn resume_position(&self, id: &str) -> Option<Duration>
+    {
+        self.state.auto_resume.then(|| self.get(id).map(|b| b.position)).flatten()
+    }
+
+    /// Send queued changes in order, then take the server's bookmarks.
+    /// Returns how many changes are still waiting, the server's list is only taken once that is none.
+    /// A change the server rejects is dropped, unless the server or the login is what's wrong.
+    pub fn sync(&mut self, server: &subsonic::Server) -> Result<usize, SlibError>
+    {
+        while let Some(change) = self.state.pending.first() {
+            let sent = match change {
+                Change::Set(bookmark) => create(server, bookmark),
+                Change::Delete(id) => delete(server, id),
+            };
+            match sent {
+                // Code 70 is not found, so there was nothing left to delete
+                Ok(()) | Err(SlibError::ServerError(70, _)) => { self.state.pending.remove(0); },
+                Err(SlibError::ServerError(code, message)) if !retry_after(code) => {
+                    log(LogLevel::Warn, format_args!("Dropped a bookmark change for {}, the server said {code}: {message}", change.id()));
+                    self.state.pending.remove(0);
+                },
+                Err(SlibError::Request(_)) => {
+                    self.save().map_err(SlibError::Io)?;
+                    return Ok(self.state.pending.len());
+                },
+                Err(e) => {
+                    self.save().map_err(SlibError::Io)?;
+                    return Err(e);
+                },
+            }
+        }
+
+        self.state.bookmarks = fetch(server)?.into_iter().map(|b| (b.item.id.clone(), b)).collect();
+        self.save().map_err(SlibError::Io)?;
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::{BufRead, BufReader, Write}, net::TcpListener, thread};
+
+    /// A Subsonic server that refuses to create bookmarks and has none
+    fn refusing_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut header = String::new();
+                while reader.read_line(&mut header).unwrap() > 2 {
+                    header.clear();
+                }
+                let body = match line.contains("createBookmark") {
+                    true => r#"{"subsonic-response":{"status":"failed","version":"1.16.1","error":{"code":50,"message":"not authorized"}}}"#,
+                    false => r#"{"subsonic-response":{"status":"ok","version":"1.16.1","bookmarks":{}}}"#,
+                };
+                write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len()).unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn offline_store() {
+        let path = std::env::temp_dir().join(format!("slib-bookmarks-{}.json", std::process::id()));
+        let item = Item { name: "Mix".to_string(), id: "7".to_string(), image_path: String::new() };
+
+        let mut store = BookmarkStore::open(&path).unwrap();
+        store.set(Bookmark { item: item.clone(), position: Duration::from_secs(60), comment: None }).unwrap();
+        store.set(Bookmark { item: item.clone(), position: Duration::from_secs(1800), comment: Some("side B".to_string()) }).unwrap();
+        assert_eq!(None, store.resume_position("7"));
+        store.set_auto_resume(true).unwrap();
+
+        // Nothing is listening on this port, so the change waits, only the latest position is sent
+        let offline = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let offline = subsonic::Server::new(offline, "user", "pass");
+        assert_eq!(1, store.sync(&offline).unwrap());
+
+        let mut store = BookmarkStore::open(&path).unwrap();
+        assert_eq!(Some(Duration::from_secs(1800)), store.resume_position("7"));
+        assert_eq!(None, store.resume_position("8"));
+        assert!(store.delete("7").unwrap());
+        assert!(!store.delete("7").unwrap());
+        assert!(store.list().is_empty());
+        assert_eq!(1, store.sync(&offline).unwrap());
+
+        // A change the server won't take doesn't hold up the ones after it
+        let other = Item { id: "8".to_string(), ..item.clone() };
+        store.set(Bookmark { item, position: Duration::from_secs(5), comment: None }).unwrap();
+        store.set(Bookmark { item: other, position: Duration::from_secs(9), comment: None }).unwrap();
+        assert_eq!(0, store.sync(&subsonic::Server::new(refusing_server(), "user", "pass")).unwrap());
+        assert!(store.list().is_empty());
+
+        fs::remove_file(path).unwrap();
+    }
+}
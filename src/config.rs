@@ -0,0 +1,327 @@
+use std::{fs, io, path::{Path, PathBuf}};
+use serde::{Deserialize, Serialize};
+
+use crate::{cover::CoverCache, Profile, ReplayGain, SlibError};
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+/// What the server should transcode streams to
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Transcoding {
+    /// `maxBitRate` in kbps, None or 0 for no limit
+    pub max_bit_rate: Option<u32>,
+    /// `format`, like "mp3" or "opus", "raw" or None for the original file
+    pub format: Option<String>,
+}
+
+impl Transcoding {
+    /// Fill in whatever isn't set from a fallback, like a request over its profile over the config
+    pub fn or(&self, fallback: &Transcoding) -> Transcoding
+    {
+        Transcoding {
+            max_bit_rate: self.max_bit_rate.or(fallback.max_bit_rate),
+            format: self.format.clone().or_else(|| fallback.format.clone()),
+        }
+    }
+
+    /// Whether this asks for the file as it is on the server, which nothing configured does too
+    pub fn is_original(&self) -> bool
+    {
+        self.format.as_deref().is_none_or(|f| f == "raw") && self.max_bit_rate.is_none_or(|b| b == 0)
+    }
+
+    /// The `stream` parameters
+    pub fn params(&self) -> Vec<(&'static str, String)>
+    {
+        let mut params = Vec::new();
+        if let Some(max_bit_rate) = self.max_bit_rate {
+            params.push(("maxBitRate", max_bit_rate.to_string()));
+        }
+        if let Some(format) = &self.format {
+            params.push(("format", format.clone()));
+        }
+        params
+    }
+}
+
+/// The daemon's settings, read from a TOML file. Anything left out takes its default.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The socket clients connect to, only read at start
+    pub socket_name: String,
+    /// Where profiles without their own cache dir keep things, in a dir named after the profile
+    pub cache_dir: PathBuf,
+    /// Megabytes the cover art cache of each profile may use
+    pub cover_cache_mb: u64,
+    /// The most detailed messages the daemon prints
+    pub log_level: LogLevel,
+    pub transcoding: Transcoding,
+    /// How long tracks overlap, 0 plays them back to back with no gap
+    pub crossfade_ms: u64,
+    pub replay_gain: ReplayGain,
+    /// The profile to use until another is switched to
+    pub default_profile: Option<String>,
+    pub profiles: Vec<Profile>,
+}
+
+/// `$XDG_CACHE_HOME/slib`, or `~/.cache/slib`
+pub(crate) fn default_cache_dir() -> PathBuf
+{
+    let cache_home = std::env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir);
+    cache_home.join("slib")
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            socket_name: crate::NAME.to_string(),
+            cache_dir: default_cache_dir(),
+            cover_cache_mb: 256,
+            log_level: LogLevel::default(),
+            transcoding: Transcoding::default(),
+            crossfade_ms: 0,
+            replay_gain: ReplayGain::default(),
+            default_profile: None,
+            profiles: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, SlibError>
+    {
+        Config::parse(&fs::read_to_string(path).map_err(SlibError::Io)?)
+    }
+
+    /// Parse and validate, errors carry the line they are about
+    pub fn parse(source: &str) -> Result<Config, SlibError>
+    {
+        let mut config: Config = toml::from_str(source).map_err(|e| {
+            let line = e.span().map_or(1, |span| line_at(source, span.start));
+            SlibError::InvalidConfig(line, e.message().to_string())
+        })?;
+        for profile in &mut config.profiles {
+            profile.resolve(&config.cache_dir);
+        }
+        config.validate(source)?;
+        Ok(config)
+    }
+
+    /// Open a profile's cover cache, trimmed to `cover_cache_mb`
+    pub fn cover_cache(&self, profile: &Profile) -> io::Result<CoverCache>
+    {
+        CoverCache::open(profile.covers_dir(), self.cover_cache_mb.saturating_mul(1024 * 1024))
+    }
+
+    fn validate(&self, source: &str) -> Result<(), SlibError>
+    {
+        let invalid = |profile: Option<usize>, table: Option<&str>, key: &str, message: String| {
+            SlibError::InvalidConfig(line_of(source, profile, table, key), message)
+        };
+
+        if self.socket_name.is_empty() {
+            return Err(invalid(None, None, "socket_name", String::from("socket_name can't be empty")));
+        }
+        if self.cover_cache_mb == 0 {
+            return Err(invalid(None, None, "cover_cache_mb", String::from("cover_cache_mb has to be more than 0")));
+        }
+        if let Some(format) = self.transcoding.format.as_ref().filter(|f| !is_format(f)) {
+            return Err(invalid(None, Some("transcoding"), "format", format!("{format:?} is not a format")));
+        }
+        for (key, db) in [("preamp", self.replay_gain.preamp), ("fallback", self.replay_gain.fallback)] {
+            if !db.is_finite() || db.abs() > 24.0 {
+                return Err(invalid(None, Some("replay_gain"), key, format!("{key} has to be from -24 to 24 dB")));
+            }
+        }
+
+        for (i, profile) in self.profiles.iter().enumerate() {
+            if let Some(format) = profile.transcoding.format.as_ref().filter(|f| !is_format(f)) {
+                return Err(invalid(Some(i), Some("transcoding"), "format", format!("{format:?} is not a format")));
+            }
+            if !profile.url.starts_with("http://") && !profile.url.starts_with("https://") {
+                return Err(invalid(Some(i), None, "url", format!("{:?} is not an http url", profile.url)));
+            }
+            if self.profiles[..i].iter().any(|p| p.name == profile.name) {
+                return Err(invalid(Some(i), None, "name", format!("there is already a profile named {:?}", profile.name)));
+            }
+            if self.profiles[..i].iter().any(|p| p.cache_dir() == profile.cache_dir()) {
+                return Err(invalid(Some(i), None, "cache_dir", format!("{} is already used by another profile", profile.cache_dir().display())));
+            }
+        }
+        if let Some(name) = &self.default_profile {
+            if !self.profiles.iter().any(|p| &p.name == name) {
+                return Err(invalid(None, None, "default_profile", format!("there is no profile named {name:?}")));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_format(format: &str) -> bool
+{
+    !format.is_empty() && format.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// The line, counting from 1, a byte offset is on
+fn line_at(source: &str, offset: usize) -> usize
+{
+    source[..offset.min(source.len())].matches('\n').count() + 1
+}
+
+/// The line a key is set on, in the nth `[[profiles]]` or at the top level, and in a table of those or not
+fn line_of(source: &str, profile: Option<usize>, table: Option<&str>, key: &str) -> usize
+{
+    let mut profiles_seen: usize = 0;
+    // The profile and table the lines so far are in
+    let mut section = (None, None);
+    // A key that was left out is blamed on its profile
+    let mut header = 1;
+    for (number, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line == "[[profiles]]" {
+            if profile == Some(profiles_seen) {
+                header = number + 1;
+            }
+            section = (Some(profiles_seen), None);
+            profiles_seen += 1;
+        }
+        else if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            // Like [profiles.transcoding], which belongs to the last [[profiles]]
+            section = match name.trim().strip_prefix("profiles.") {
+                Some(name) => (profiles_seen.checked_sub(1), Some(name.trim())),
+                None => (None, Some(name.trim())),
+            };
+        }
+        else if section == (profile, table)
+            && line.strip_prefix(key).is_some_and(|rest| rest.trim_start().starts_with('='))
+        {
+            return number + 1;
+        }
+    }
+    // An inline table is all on the line of its own key
+    match table {
+        Some(table) => line_of(source, profile, None, table),
+        None => header,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_validate() {
+        let config = Config::parse(r#"
+            log_level = "debug"
+            default_profile = "home"
+
+            [transcoding]
+            max_bit_rate = 192
+            format = "opus"
+
+            [[profiles]]
+            name = "home"
+            url = "https://music.home"
+            user = "me"
+            credential = "me@https://music.home"
+            cache_dir = "/var/cache/slib/home"
+        "#).unwrap();
+        assert_eq!(LogLevel::Debug, config.log_level);
+        assert_eq!(Some(192), config.transcoding.max_bit_rate);
+        assert_eq!(256, config.cover_cache_mb);
+        assert_eq!(crate::NAME, config.socket_name);
+        assert_eq!("home", config.profiles[0].name);
+        assert_eq!(PathBuf::from("/var/cache/slib/home/covers"), config.profiles[0].covers_dir());
+
+        fn line(source: &str) -> usize {
+            match Config::parse(source) {
+                Err(SlibError::InvalidConfig(line, _)) => line,
+                other => panic!("{other:?}"),
+            }
+        }
+        assert_eq!(3, line("log_level = \"info\"\n\ncover_cache_mb = \"lots\"\n"));
+        assert_eq!(2, line("log_level = \"info\"\nlog_levle = \"info\"\n"));
+        assert_eq!(1, line("default_profile = \"work\"\n"));
+
+        let profile = "[[profiles]]\nname = \"a\"\nurl = \"https://a\"\nuser = \"u\"\ncredential = \"c\"\n";
+        let source = format!("{profile}cache_dir = \"/a\"\n{profile}cache_dir = \"/b\"\n");
+        // The second profile reuses the name
+        assert_eq!(8, line(&source));
+        let source = format!("{profile}cache_dir = \"/a\"\n{}cache_dir = \"/b\"\n", profile.replace("https://a", "ftp://a"));
+        assert_eq!(9, line(&source));
+
+        // Nested tables, as their own header or inline
+        let source = format!("{profile}cache_dir = \"/a\"\n\n[profiles.transcoding]\nmax_bit_rate = 128\nformat = \"no good\"\n");
+        assert_eq!(10, line(&source));
+        let source = format!("{profile}cache_dir = \"/a\"\ntranscoding = {{ format = \"no good\" }}\n");
+        assert_eq!(7, line(&source));
+
+        // Profiles are as strict as the top level
+        assert_eq!(6, line(&format!("{profile}cahce_dir = \"/a\"\n")));
+        // Without their own cache dir they get one in the config's, which has to be theirs alone
+        let config = Config::parse(&format!("cache_dir = \"/cache\"\n{profile}")).unwrap();
+        assert_eq!(PathBuf::from("/cache/a/lyrics"), config.profiles[0].lyrics_dir());
+        assert_eq!(12, line(&format!("cache_dir = \"/cache\"\n{profile}{}cache_dir = \"/cache/a\"\n", profile.replace("\"a\"", "\"b\""))));
+        assert_eq!(8, line(&format!("cache_dir = \"/cache\"\n{}cache_dir = \"/cache/a\"\n{profile}", profile.replace("\"a\"", "\"b\""))));
+        assert_eq!(3, line("log_level = \"info\"\n[transcoding]\nformat = \"?\"\n"));
+        assert_eq!(3, line("[replay_gain]\nmode = \"track\"\npreamp = nan\n"));
+    }
+
+    #[test]
+    fn cover_budget() {
+        let dir = std::env::temp_dir().join(format!("slib-config-covers-{}", std::process::id()));
+        let config = Config::parse(&format!("cover_cache_mb = 1\ncache_dir = {:?}\n", dir.display().to_string())).unwrap();
+        let mut profile = Profile {
+            name: "home".to_string(),
+            url: "https://music.home".to_string(),
+            user: "me".to_string(),
+            credential: "me@https://music.home".to_string(),
+            cache_dir: None,
+            transcoding: Transcoding::default(),
+        };
+        profile.resolve(&config.cache_dir);
+        let cache = config.cover_cache(&profile).unwrap();
+
+        // Two images of 600 KiB don't fit in 1 MiB, the older one goes
+        cache.insert("al-1", None, &vec![0; 600 * 1024]).unwrap();
+        cache.insert("al-2", None, &vec![0; 600 * 1024]).unwrap();
+        assert_eq!(None, cache.get("al-1", None));
+        assert!(cache.get("al-2", None).is_some());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn transcoding() {
+        let profile = Transcoding { max_bit_rate: Some(128), format: Some("opus".to_string()) };
+        let request = Transcoding { max_bit_rate: None, format: Some("raw".to_string()) };
+        let quality = request.or(&profile);
+        assert_eq!(Some(128), quality.max_bit_rate);
+        assert!(!quality.is_original());
+        assert!(request.is_original());
+
+        let server = crate::subsonic::Server::new("https://music.example", "me", "pass");
+        let url = server.stream_url("42", &profile);
+        assert!(url.contains("/rest/stream?") && url.ends_with("&id=42&maxBitRate=128&format=opus"));
+        // `download` always sends the original, so anything else has to be streamed
+        assert!(server.download_url("42", &profile).contains("/rest/stream?"));
+        assert!(server.download_url("42", &request).contains("/rest/download?"));
+        // Nothing set anywhere keeps downloads away from the server's own transcoding
+        assert!(Transcoding::default().is_original());
+        assert!(server.download_url("42", &Transcoding::default()).contains("/rest/download?"));
+        assert!(!Transcoding { max_bit_rate: Some(128), format: None }.is_original());
+    }
+}
@@ -0,0 +1,189 @@
+use std::{fs::{self, File}, io::{self, Cursor}, path::{Path, PathBuf}, time::SystemTime};
+use image::imageops::FilterType;
+use serde::{Deserialize, Serialize};
+
+use crate::{subsonic, SlibError};
+
+/// Cover art as returned over the socket
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub enum CoverArt {
+    /// Where the image is in the daemon's cache
+    Path(PathBuf),
+    /// The image itself, for clients that can't read the daemon's files
+    Bytes(Vec<u8>),
+}
+
+impl CoverArt {
+    /// Return a cached image either by path or read into memory
+    pub fn from_path(path: PathBuf, inline: bool) -> io::Result<CoverArt>
+    {
+        if inline {
+            Ok(CoverArt::Bytes(fs::read(path)?))
+        }
+        else {
+            Ok(CoverArt::Path(path))
+        }
+    }
+}
+
+/// Cover art kept on disk, trimmed back to a size budget by least recent use
+pub struct CoverCache {
+    dir: PathBuf,
+    /// Bytes the cache may use
+    budget: u64,
+}
+
+impl CoverCache {
+    pub fn open(dir: impl Into<PathBuf>, budget: u64) -> io::Result<CoverCache>
+    {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(CoverCache{dir, budget})
+    }
+
+    /// Where an image of a size is kept, no size is the original
+    fn path(&self, id: &str, size: Option<u32>) -> PathBuf
+    {
+        // Hashed, so ids that only differ in characters a file name can't have stay apart
+        let id = format!("{:x}", md5::compute(id));
+        match size {
+            Some(size) => self.dir.join(format!("{id}-{size}")),
+            None => self.dir.join(format!("{id}-full")),
+        }
+    }
+
+    /// Look up a cached image, marking it as recently used
+    pub fn get(&self, id: &str, size: Option<u32>) -> Option<PathBuf>
+    {
+        let path = self.path(id, size);
+        let file = File::options().append(true).open(&path).ok()?;
+        let _ = file.set_modified(SystemTime::now());
+        Some(path)
+    }
+
+    /// Add an image to the cache, then trim the others back to the budget.
+    /// The new image is kept even if it doesn't fit on its own.
+    pub fn insert(&self, id: &str, size: Option<u32>, bytes: &[u8]) -> io::Result<PathBuf>
+    {
+        let path = self.path(id, size);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, &path)?;
+        self.trim_keeping(Some(&path))?;
+        Ok(path)
+    }
+
+    /// Return a cached image, downloading it with `getCoverArt` if needed.
+    /// The original is downloaded once and every size is scaled from it here, only images
+    /// that can't be decoded are left to the server to scale.
+    pub fn fetch(&self, server: &subsonic::Server, id: &str, size: Option<u32>) -> Result<PathBuf, SlibError>
+    {
+        if let Some(path) = self.get(id, size) {
+            return Ok(path);
+        }
+
+        let original = match self.get(id, None) {
+            Some(path) => path,
+            None => self.insert(id, None, &server.fetch("getCoverArt", &[("id", id)])?).map_err(SlibError::Io)?,
+        };
+        let Some(size) = size else { return Ok(original) };
+
+        let bytes = match resize(&fs::read(&original).map_err(SlibError::Io)?, size) {
+            Some(bytes) => bytes,
+            None => server.fetch("getCoverArt", &[("id", id), ("size", &size.to_string())])?,
+        };
+        self.insert(id, Some(size), &bytes).map_err(SlibError::Io)
+    }
+
+    /// Delete the least recently used images until the cache fits its budget, returns bytes freed
+    pub fn trim(&self) -> io::Result<u64>
+    {
+        self.trim_keeping(None)
+    }
+
+    fn trim_keeping(&self, keep: Option<&Path>) -> io::Result<u64>
+    {
+        let mut files = Vec::new();
+        let mut total = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                total += metadata.len();
+                files.push((metadata.modified()?, metadata.len(), entry.path()));
+            }
+        }
+
+        // Oldest first
+        files.sort();
+        let mut freed = 0;
+        for (_, len, path) in files {
+            if total - freed <= self.budget {
+                break;
+            }
+            if keep == Some(path.as_path()) {
+                continue;
+            }
+            fs::remove_file(path)?;
+            freed += len;
+        }
+        Ok(freed)
+    }
+}
+
+/// Scale an image down to fit a square of `size` pixels, keeping its format.
+/// Images already that small come back as they are, None if it can't be decoded.
+pub fn resize(bytes: &[u8], size: u32) -> Option<Vec<u8>>
+{
+    let format = image::guess_format(bytes).ok()?;
+    let image = image::load_from_memory_with_format(bytes, format).ok()?;
+    if image.width() <= size && image.height() <= size {
+        return Some(bytes.to_vec());
+    }
+    let mut out = Cursor::new(Vec::new());
+    image.resize(size, size, FilterType::Lanczos3).write_to(&mut out, format).ok()?;
+    Some(out.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget() {
+        let dir = std::env::temp_dir().join(format!("slib-covers-{}", std::process::id()));
+        let cache = CoverCache::open(&dir, 10).unwrap();
+
+        cache.insert("al-1", Some(64), b"abcdef").unwrap();
+        assert!(cache.get("al-1", Some(64)).is_some());
+        assert!(cache.get("al-1", None).is_none());
+
+        // Only one of them fits, so the older one goes
+        cache.insert("al/2", None, b"ghijkl").unwrap();
+        assert!(cache.get("al-1", Some(64)).is_none());
+        assert_eq!(
+            CoverArt::Bytes(b"ghijkl".to_vec()),
+            CoverArt::from_path(cache.get("al/2", None).unwrap(), true).unwrap()
+        );
+        assert!(cache.get("al_2", None).is_none());
+
+        // Too big on its own still stays, having pushed out everything else
+        let path = cache.insert("al-3", None, b"far over the budget").unwrap();
+        assert!(path.exists());
+        assert!(cache.get("al/2", None).is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn thumbnails() {
+        let mut png = Cursor::new(Vec::new());
+        image::RgbImage::new(100, 50).write_to(&mut png, image::ImageFormat::Png).unwrap();
+        let png = png.into_inner();
+
+        let thumbnail = image::load_from_memory(&resize(&png, 32).unwrap()).unwrap();
+        assert_eq!((32, 16), (thumbnail.width(), thumbnail.height()));
+        assert_eq!(png, resize(&png, 100).unwrap());
+        assert!(resize(b"not an image", 32).is_none());
+    }
+}
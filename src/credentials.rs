@@ -0,0 +1,224 @@
+use std::{collections::BTreeMap, fmt, fs, io, path::{Path, PathBuf}};
+use chacha20poly1305::{aead::{Aead, AeadCore, KeyInit, OsRng, Payload}, ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+
+use crate::{log, subsonic, LogLevel, SlibError};
+
+/// A password or API key. It prints as `***` so it doesn't end up in logs.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(transparent)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+/// Somewhere secrets are kept, like an encrypted file or the OS keyring
+pub trait Keyring {
+    fn get(&self, name: &str) -> Result<Option<Secret>, SlibError>;
+    fn set(&mut self, name: &str, secret: &Secret) -> Result<(), SlibError>;
+    /// Returns false if there was nothing to delete
+    fn delete(&mut self, name: &str) -> Result<bool, SlibError>;
+}
+
+/// The name a server login is kept under, and what goes in `Profile::credential`
+pub fn credential_name(url: &str, user: &str) -> String
+{
+    format!("{user}@{}", url.trim_end_matches('/'))
+}
+
+/// Check a login with `ping`, and only keep it if the server takes it.
+/// Returns the name it was kept under.
+pub fn login(keyring: &mut dyn Keyring, url: &str, user: &str, password: &Secret) -> Result<String, SlibError>
+{
+    subsonic::Server::new(url, user, &password.0).ping()?;
+    let name = credential_name(url, user);
+    keyring.set(&name, password)?;
+    Ok(name)
+}
+
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    nonce: Vec<u8>,
+    data: Vec<u8>,
+}
+
+/// Secrets encrypted with ChaCha20-Poly1305 in a local file.
+///
+/// The key is made on first use and kept in its own file, readable only by the owner,
+/// so it can be put somewhere other than the secrets, like a separate volume.
+/// Existing files that others could read, like copies from an older install, are made private on open.
+pub struct FileKeyring {
+    path: PathBuf,
+    cipher: ChaCha20Poly1305,
+    secrets: BTreeMap<String, Sealed>,
+}
+
+impl FileKeyring {
+    pub fn open(path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> io::Result<FileKeyring>
+    {
+        let path = path.into();
+        let key_path = key_path.into();
+
+        let key = match fs::read(&key_path) {
+            Ok(key) if key.len() == 32 => {
+                make_private(&key_path)?;
+                key
+            },
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "the key file is not 32 bytes")),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let key = ChaCha20Poly1305::generate_key(&mut OsRng).to_vec();
+                write_private(&key_path, &key)?;
+                key
+            },
+            Err(e) => return Err(e),
+        };
+
+        let secrets = match fs::read_to_string(&path) {
+            Ok(data) => {
+                make_private(&path)?;
+                serde_json::from_str(&data)?
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(FileKeyring { path, cipher: ChaCha20Poly1305::new(Key::from_slice(&key)), secrets })
+    }
+
+    fn save(&self) -> io::Result<()>
+    {
+        let tmp = self.path.with_extension("tmp");
+        write_private(&tmp, serde_json::to_string(&self.secrets)?.as_bytes())?;
+        fs::rename(tmp, &self.path)
+    }
+}
+
+impl Keyring for FileKeyring {
+    fn get(&self, name: &str) -> Result<Option<Secret>, SlibError> {
+        let Some(sealed) = self.secrets.get(name) else { return Ok(None) };
+        // ChaCha20-Poly1305 nonces are 12 bytes, anything else was damaged or edited
+        if sealed.nonce.len() != 12 {
+            return Err(SlibError::Io(io::Error::new(io::ErrorKind::InvalidData, format!("{name} has a broken nonce"))));
+        }
+        // The name is bound in, so a secret can't be moved to another name
+        let plain = self.cipher.decrypt(Nonce::from_slice(&sealed.nonce), Payload {
+            msg: &sealed.data,
+            aad: name.as_bytes(),
+        });
+        match plain.ok().and_then(|p| String::from_utf8(p).ok()) {
+            Some(secret) => Ok(Some(Secret(secret))),
+            None => Err(SlibError::Io(io::Error::new(io::ErrorKind::InvalidData, format!("{name} could not be decrypted")))),
+        }
+    }
+
+    fn set(&mut self, name: &str, secret: &Secret) -> Result<(), SlibError> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let data = self.cipher.encrypt(&nonce, Payload {
+            msg: secret.0.as_bytes(),
+            aad: name.as_bytes(),
+        }).map_err(|_| SlibError::Io(io::Error::other("encryption failed")))?;
+        self.secrets.insert(name.to_string(), Sealed { nonce: nonce.to_vec(), data });
+        self.save().map_err(SlibError::Io)
+    }
+
+    fn delete(&mut self, name: &str) -> Result<bool, SlibError> {
+        if self.secrets.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.save().map_err(SlibError::Io)?;
+        Ok(true)
+    }
+}
+
+/// Take away any access the group or others have to an existing file
+fn make_private(path: &Path) -> io::Result<()>
+{
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            log(LogLevel::Warn, format_args!("{} was open to other users, it is now private", path.display()));
+            fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o700))?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Write a file only the owner can read, tightening it first if it already exists
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()>
+{
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // The mode only applies to files that are created
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    io::Write::write_all(&mut file, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn encrypted_at_rest() {
+        let dir = std::env::temp_dir().join(format!("slib-credentials-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = credential_name("https://music.example/", "user");
+        assert_eq!("user@https://music.example", name);
+
+        let mut keyring = FileKeyring::open(dir.join("secrets.json"), dir.join("key")).unwrap();
+        keyring.set(&name, &Secret("hunter2".to_string())).unwrap();
+        assert!(!fs::read_to_string(dir.join("secrets.json")).unwrap().contains("hunter2"));
+        assert_eq!("***", format!("{:?}", Secret("hunter2".to_string())));
+
+        // Survives a restart with the same key
+        let mut keyring = FileKeyring::open(dir.join("secrets.json"), dir.join("key")).unwrap();
+        assert_eq!(Some(Secret("hunter2".to_string())), keyring.get(&name).unwrap());
+        assert!(keyring.delete(&name).unwrap());
+        assert_eq!(None, keyring.get(&name).unwrap());
+
+        // A damaged nonce is an error rather than a panic
+        fs::write(dir.join("secrets.json"), format!(r#"{{"{name}": {{"nonce": [1, 2], "data": [3]}}}}"#)).unwrap();
+        let mut keyring = FileKeyring::open(dir.join("secrets.json"), dir.join("key")).unwrap();
+        assert!(keyring.get(&name).is_err());
+
+        // Files left readable by others are tightened when written again
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::write(dir.join("secrets.tmp"), "").unwrap();
+            fs::set_permissions(dir.join("secrets.tmp"), fs::Permissions::from_mode(0o644)).unwrap();
+            keyring.set(&name, &Secret("hunter2".to_string())).unwrap();
+            assert_eq!(0o600, fs::metadata(dir.join("secrets.json")).unwrap().permissions().mode() & 0o777);
+
+            // And when they are opened, before anything is written
+            for file in ["key", "secrets.json"] {
+                fs::set_permissions(dir.join(file), fs::Permissions::from_mode(0o644)).unwrap();
+            }
+            keyring = FileKeyring::open(dir.join("secrets.json"), dir.join("key")).unwrap();
+            for file in ["key", "secrets.json"] {
+                assert_eq!(0o600, fs::metadata(dir.join(file)).unwrap().permissions().mode() & 0o777, "{file}");
+            }
+            assert_eq!(Some(Secret("hunter2".to_string())), keyring.get(&name).unwrap());
+        }
+
+        // A login that can't be checked isn't kept
+        let offline = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        assert!(login(&mut keyring, &offline, "user", &Secret("hunter2".to_string())).is_err());
+        assert_eq!(None, keyring.get(&credential_name(&offline, "user")).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
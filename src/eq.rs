@@ -0,0 +1,264 @@
+use std::{collections::BTreeMap, f32::consts::PI, fs, io, path::PathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::playback::Format;
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum BandKind {
+    /// Boost or cut around the frequency
+    Peak,
+    /// Boost or cut everything below the frequency
+    LowShelf,
+    /// Boost or cut everything above the frequency
+    HighShelf,
+}
+
+/// One filter of the equalizer
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+pub struct Band {
+    pub kind: BandKind,
+    /// Center or corner frequency in Hz
+    pub frequency: f32,
+    /// dB, negative cuts
+    pub gain: f32,
+    /// How narrow the band is, 0.707 is a gentle shelf
+    pub q: f32,
+}
+
+impl Band {
+    pub fn is_valid(&self, format: Format) -> bool
+    {
+        self.frequency > 0.0
+            && self.frequency < format.sample_rate as f32 / 2.0
+            && self.q > 0.0
+            && self.gain.abs() <= 24.0
+    }
+}
+
+/// The built in presets, none of which can be saved over
+pub fn preset(name: &str) -> Option<Vec<Band>>
+{
+    let band = |kind, frequency, gain, q| Band { kind, frequency, gain, q };
+    match name.to_lowercase().as_str() {
+        "flat" => Some(Vec::new()),
+        "bass boost" => Some(vec![band(BandKind::LowShelf, 100.0, 6.0, 0.707)]),
+        "voice" => Some(vec![
+            band(BandKind::LowShelf, 150.0, -4.0, 0.707),
+            band(BandKind::Peak, 2500.0, 4.0, 1.0),
+            band(BandKind::HighShelf, 8000.0, -2.0, 0.707),
+        ]),
+        _ => None,
+    }
+}
+
+/// A second order filter, from the Audio EQ Cookbook
+#[derive(Debug, Clone)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    /// Two delays for every channel
+    state: Vec<[f32; 2]>,
+}
+
+impl Biquad {
+    fn new(band: &Band, format: Format) -> Biquad
+    {
+        let a = 10f32.powf(band.gain / 40.0);
+        let w0 = 2.0 * PI * band.frequency / format.sample_rate as f32;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * band.q);
+        let shelf = 2.0 * a.sqrt() * alpha;
+
+        let (b0, b1, b2, a0, a1, a2) = match band.kind {
+            BandKind::Peak => (
+                1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a,
+                1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a,
+            ),
+            BandKind::LowShelf => (
+                a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                (a + 1.0) + (a - 1.0) * cos + shelf,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - shelf,
+            ),
+            BandKind::HighShelf => (
+                a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                (a + 1.0) - (a - 1.0) * cos + shelf,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - shelf,
+            ),
+        };
+        Biquad {
+            b0: b0 / a0, b1: b1 / a0, b2: b2 / a0, a1: a1 / a0, a2: a2 / a0,
+            state: vec![[0.0; 2]; format.channels as usize],
+        }
+    }
+
+    /// Transposed direct form II
+    fn process(&mut self, samples: &mut [f32])
+    {
+        let channels = self.state.len();
+        for (i, sample) in samples.iter_mut().enumerate() {
+            let z = &mut self.state[i % channels];
+            let x = *sample;
+            let y = self.b0 * x + z[0];
+            z[0] = self.b1 * x - self.a1 * y + z[1];
+            z[1] = self.b2 * x - self.a2 * y;
+            *sample = y;
+        }
+    }
+}
+
+/// A chain of biquad filters over interleaved samples
+#[derive(Debug, Clone)]
+pub struct Equalizer {
+    bands: Vec<Band>,
+    filters: Vec<Biquad>,
+}
+
+impl Equalizer {
+    /// None if a band doesn't fit the format, like a frequency over Nyquist
+    pub fn new(format: Format, bands: &[Band]) -> Option<Equalizer>
+    {
+        if !bands.iter().all(|band| band.is_valid(format)) {
+            return None;
+        }
+        Some(Equalizer {
+            bands: bands.to_vec(),
+            filters: bands.iter().map(|band| Biquad::new(band, format)).collect(),
+        })
+    }
+
+    pub fn bands(&self) -> &[Band]
+    {
+        &self.bands
+    }
+
+    pub fn process(&mut self, samples: &mut [f32])
+    {
+        for filter in &mut self.filters {
+            filter.process(samples);
+        }
+    }
+}
+
+/// Presets saved by name, alongside the built in ones
+pub struct PresetStore {
+    path: PathBuf,
+    presets: BTreeMap<String, Vec<Band>>,
+}
+
+impl PresetStore {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<PresetStore>
+    {
+        let path = path.into();
+        let presets = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(PresetStore{path, presets})
+    }
+
+    /// Saved presets first, then the built in ones
+    pub fn load(&self, name: &str) -> Option<Vec<Band>>
+    {
+        self.presets.get(name).cloned().or_else(|| preset(name))
+    }
+
+    /// Returns false for the name of a built in preset
+    pub fn save(&mut self, name: &str, bands: &[Band]) -> io::Result<bool>
+    {
+        if preset(name).is_some() {
+            return Ok(false);
+        }
+        self.presets.insert(name.to_string(), bands.to_vec());
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string(&self.presets)?)?;
+        fs::rename(tmp, &self.path)?;
+        Ok(true)
+    }
+
+    pub fn names(&self) -> Vec<String>
+    {
+        ["flat", "bass boost", "voice"].into_iter().map(String::from)
+            .chain(self.presets.keys().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{playback::{BufferSource, Engine, FileSink}, replaygain::GainInfo};
+
+    const FORMAT: Format = Format { sample_rate: 48000, channels: 2 };
+
+    fn sine(frequency: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let s = (2.0 * PI * frequency * i as f32 / FORMAT.sample_rate as f32).sin() * 0.25;
+                [s, s]
+            })
+            .collect()
+    }
+
+    /// RMS of the left channel once the filters have settled
+    fn level(samples: &[f32]) -> f32 {
+        let left: Vec<f32> = samples.iter().step_by(2).skip(4800).copied().collect();
+        (left.iter().map(|s| s * s).sum::<f32>() / left.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn peak_band() {
+        let mut eq = Equalizer::new(FORMAT, &[Band { kind: BandKind::Peak, frequency: 1000.0, gain: 6.0, q: 1.0 }]).unwrap();
+        let mut at = sine(1000.0, 24000);
+        let mut away = sine(100.0, 24000);
+        let before = level(&at);
+        eq.process(&mut at);
+        // Fresh filter state for the second tone
+        eq = Equalizer::new(FORMAT, eq.bands()).unwrap();
+        eq.process(&mut away);
+
+        let db = |after: f32| 20.0 * (after / before).log10();
+        assert!((db(level(&at)) - 6.0).abs() < 0.1);
+        assert!(db(level(&away)).abs() < 0.5);
+        assert!(Equalizer::new(FORMAT, &[Band { kind: BandKind::Peak, frequency: 30000.0, gain: 6.0, q: 1.0 }]).is_none());
+    }
+
+    #[test]
+    fn presets_through_engine() {
+        let dir = std::env::temp_dir().join(format!("slib-eq-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut presets = PresetStore::open(dir.join("presets.json")).unwrap();
+        assert!(!presets.save("flat", &[]).unwrap());
+        assert!(presets.save("mine", &preset("Bass Boost").unwrap()).unwrap());
+        let presets = PresetStore::open(dir.join("presets.json")).unwrap();
+        assert_eq!(4, presets.names().len());
+
+        // Render a low tone through the engine into a file and read it back
+        let render = |bands: Vec<Band>| {
+            let path = dir.join("out.raw");
+            let mut engine = Engine::new(FORMAT);
+            assert!(engine.set_eq(&bands));
+            engine.play(Box::new(BufferSource::new(FORMAT, sine(50.0, 24000))), GainInfo::default());
+            engine.play_into(&mut FileSink::create(&path).unwrap(), 24000).unwrap();
+            let bytes = fs::read(&path).unwrap();
+            bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect::<Vec<_>>()
+        };
+        let flat = render(presets.load("flat").unwrap());
+        let boosted = render(presets.load("mine").unwrap());
+        assert_eq!(sine(50.0, 24000), flat);
+        assert!((20.0 * (level(&boosted) / level(&flat)).log10() - 6.0).abs() < 0.5);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,364 @@
+use std::{collections::{BTreeMap, BTreeSet, HashMap, HashSet}, fs, io, path::Path};
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{Item, SearchQuery, SearchResults, SongInfo};
+
+/// The kinds of things kept in the index
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Category {
+    Song,
+    Album,
+    Artist,
+    Playlist,
+}
+
+/// An inverted index over the names of one category
+#[derive(Serialize, Deserialize, Default)]
+struct Terms {
+    items: HashMap<String, Item>,
+    /// Sorted so prefixes can be looked up as a range
+    terms: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Terms {
+    fn insert(&mut self, item: &Item) {
+        self.remove(&item.id);
+        for term in terms(&item.name) {
+            self.terms.entry(term).or_default().insert(item.id.clone());
+        }
+        self.items.insert(item.id.clone(), item.clone());
+    }
+
+    fn remove(&mut self, id: &str) {
+        let Some(old) = self.items.remove(id) else { return };
+        for term in terms(&old.name) {
+            if let Some(ids) = self.terms.get_mut(&term) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.terms.remove(&term);
+                }
+            }
+        }
+    }
+
+    /// Score every item against a single query word
+    fn lookup(&self, word: &str) -> HashMap<&str, u32> {
+        let mut scores: HashMap<&str, u32> = HashMap::new();
+        let mut score = |ids: &BTreeSet<String>, points: u32| {
+            for id in ids {
+                let best = scores.entry(self.items[id].id.as_str()).or_default();
+                *best = (*best).max(points);
+            }
+        };
+
+        // Prefixes, which includes the exact match, for type-ahead
+        for (term, ids) in self.terms.range(word.to_string()..) {
+            if !term.starts_with(word) {
+                break;
+            }
+            score(ids, if term == word { 3 } else { 2 });
+        }
+
+        // Typos, counting characters like the distance does
+        let max = max_distance(word);
+        let len = word.chars().count();
+        if max > 0 {
+            for (term, ids) in &self.terms {
+                if term.chars().count().abs_diff(len) <= max && distance(term, word, max) <= max {
+                    score(ids, 1);
+                }
+            }
+        }
+        scores
+    }
+
+    /// Items matching every word and the filter, best first. With no words everything that passes the filter matches.
+    fn search(&self, words: &[String], limit: usize, keep: impl Fn(&Item) -> bool) -> Vec<Item> {
+        let mut totals: Option<HashMap<&str, u32>> = None;
+        for word in words {
+            let scores = self.lookup(word);
+            totals = Some(match totals {
+                None => scores,
+                Some(totals) => totals.into_iter()
+                    .filter_map(|(id, total)| scores.get(id).map(|s| (id, total + s)))
+                    .collect(),
+            });
+        }
+
+        let totals = totals.unwrap_or_else(|| self.items.keys().map(|id| (id.as_str(), 0)).collect());
+        let mut found: Vec<(u32, &Item)> = totals
+            .into_iter()
+            .map(|(id, score)| (score, &self.items[id]))
+            .filter(|(_, item)| keep(item))
+            .collect();
+        found.sort_by(|(a, x), (b, y)| b.cmp(a).then_with(|| x.name.cmp(&y.name)));
+        found.into_iter().take(limit).map(|(_, item)| item.clone()).collect()
+    }
+}
+
+/// A local full-text index of the library for searching offline or with typos
+#[derive(Serialize, Deserialize, Default)]
+pub struct SearchIndex {
+    songs: Terms,
+    albums: Terms,
+    artists: Terms,
+    playlists: Terms,
+    /// What is known about songs past their names, for the field filters
+    #[serde(default)]
+    song_info: HashMap<String, SongInfo>,
+}
+
+impl SearchIndex {
+    pub fn load(path: &Path) -> io::Result<SearchIndex>
+    {
+        match fs::read_to_string(path) {
+            Ok(data) => Ok(serde_json::from_str(&data)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(SearchIndex::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()>
+    {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string(self)?)?;
+        fs::rename(tmp, path)
+    }
+
+    fn category(&mut self, category: Category) -> &mut Terms {
+        match category {
+            Category::Song => &mut self.songs,
+            Category::Album => &mut self.albums,
+            Category::Artist => &mut self.artists,
+            Category::Playlist => &mut self.playlists,
+        }
+    }
+
+    /// Add or update items, as `fetch_*` returns them
+    pub fn update(&mut self, category: Category, items: &[Item])
+    {
+        let terms = self.category(category);
+        for item in items {
+            terms.insert(item);
+        }
+    }
+
+    /// Add or update songs along with their artist, album, genre and year, so the field filters can find them
+    pub fn update_songs(&mut self, songs: &[(Item, SongInfo)])
+    {
+        for (song, info) in songs {
+            self.songs.insert(song);
+            self.song_info.insert(song.id.clone(), info.clone());
+        }
+    }
+
+    /// Replace everything in a category, after a `scan` or a full fetch
+    pub fn replace(&mut self, category: Category, items: &[Item])
+    {
+        *self.category(category) = Terms::default();
+        if category == Category::Song {
+            self.song_info.clear();
+        }
+        self.update(category, items);
+    }
+
+    pub fn remove(&mut self, category: Category, id: &str)
+    {
+        self.category(category).remove(id);
+        if category == Category::Song {
+            self.song_info.remove(id);
+        }
+    }
+
+    /// Search the index. Free text is matched in every category and each field filter
+    /// in the category it names, songs are also held to the filters on their artist, album, genre and year.
+    pub fn search(&self, query: &SearchQuery) -> SearchResults
+    {
+        let text = terms(&query.text);
+        let with = |field: &Option<String>| -> Vec<String> {
+            text.iter().cloned().chain(field.iter().flat_map(|f| terms(f))).collect()
+        };
+        let song_filters = query.artist.is_some() || query.album.is_some() || query.genre.is_some() || query.year.is_some();
+        let field_filters = song_filters || query.title.is_some();
+        if text.is_empty() && !field_filters {
+            return SearchResults::default();
+        }
+
+        let songs = with(&query.title);
+        let albums = with(&query.album);
+        let artists = with(&query.artist);
+        // Who an album is by is only known from its songs, so find the albums by the artist once
+        let artist_albums = query.artist.as_ref().map(|artist| {
+            let artist = fold(artist);
+            self.song_info.values()
+                .filter(|info| fold(&info.artist).contains(&artist))
+                .map(|info| info.album.id.as_str())
+                .collect::<HashSet<_>>()
+        });
+        SearchResults {
+            songs: match songs.is_empty() && !song_filters {
+                true => Vec::new(),
+                false => self.songs.search(&songs, query.limits.songs, |song| {
+                    !song_filters || self.song_info.get(&song.id).is_some_and(|info| query.matches_song(song, info))
+                }),
+            },
+            albums: match albums.is_empty() && query.artist.is_none() {
+                true => Vec::new(),
+                false => self.albums.search(&albums, query.limits.albums, |album| {
+                    artist_albums.as_ref().is_none_or(|ids| ids.contains(album.id.as_str()))
+                }),
+            },
+            artists: match artists.is_empty() {
+                true => Vec::new(),
+                false => self.artists.search(&artists, query.limits.artists, |_| true),
+            },
+            // Playlists have none of the fields
+            playlists: match text.is_empty() || field_filters {
+                true => Vec::new(),
+                false => self.playlists.search(&text, query.limits.playlists, |_| true),
+            },
+        }
+    }
+}
+
+/// Lowercase and strip diacritics, so "Björk" and "bjork" are the same
+pub fn fold(text: &str) -> String
+{
+    let mut out = String::with_capacity(text.len());
+    for c in text.nfkd() {
+        match c {
+            // Combining marks left over from decomposing
+            '\u{0300}'..='\u{036f}' => {},
+            // Letters that don't decompose
+            'ß' => out += "ss",
+            'æ' | 'Æ' => out += "ae",
+            'œ' | 'Œ' => out += "oe",
+            'ø' | 'Ø' => out.push('o'),
+            'ł' | 'Ł' => out.push('l'),
+            'đ' | 'Đ' | 'ð' | 'Ð' => out.push('d'),
+            'þ' | 'Þ' => out += "th",
+            c => out.extend(c.to_lowercase()),
+        }
+    }
+    out
+}
+
+/// Split text into folded words
+fn terms(text: &str) -> Vec<String>
+{
+    fold(text)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// How many typos to allow in a word, short words have to be exact
+fn max_distance(word: &str) -> usize
+{
+    match word.chars().count() {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+/// Levenshtein distance, giving up once it is over `max`
+fn distance(a: &str, b: &str, max: usize) -> usize
+{
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitute.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        if current.iter().all(|&d| d > max) {
+            return max + 1;
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(id: &str, name: &str) -> Item {
+        Item { name: name.to_string(), id: id.to_string(), image_path: String::new() }
+    }
+
+    fn names(items: Vec<Item>) -> Vec<String> {
+        items.into_iter().map(|i| i.name).collect()
+    }
+
+    #[test]
+    fn fuzzy_folded_prefix() {
+        let mut index = SearchIndex::default();
+        index.update(Category::Artist, &[item("1", "Björk"), item("2", "John Coltrane"), item("3", "Beyoncé")]);
+        index.update(Category::Song, &[item("1", "Jóga"), item("2", "Giant Steps"), item("3", "Naima")]);
+
+        fn search(index: &SearchIndex, text: &str) -> SearchResults {
+            index.search(&SearchQuery::parse(text))
+        }
+        assert_eq!(vec!["Björk"], names(search(&index, "bjork").artists));
+        assert_eq!(vec!["Beyoncé"], names(search(&index, "BEYONCE").artists));
+        assert_eq!(vec!["John Coltrane"], names(search(&index, "coltraine").artists));
+        assert_eq!(vec!["John Coltrane"], names(search(&index, "john col").artists));
+        assert_eq!(vec!["Giant Steps"], names(search(&index, "gi").songs));
+        assert!(search(&index, "xyz").songs.is_empty());
+
+        // Renames drop the old terms
+        index.update(Category::Song, &[item("3", "Equinox")]);
+        assert!(search(&index, "naima").songs.is_empty());
+        index.replace(Category::Song, &[]);
+        assert!(search(&index, "equinox").songs.is_empty());
+    }
+
+    #[test]
+    fn field_filters() {
+        let info = |artist: &str, album: Item| SongInfo {
+            length: std::time::Duration::from_secs(300),
+            album,
+            artist: artist.to_string(),
+            genre: None,
+            year: None,
+            rating: None,
+            starred: false,
+        };
+        let mut index = SearchIndex::default();
+        index.update(Category::Album, &[item("al-1", "Kind of Blue"), item("al-2", "Blue Train")]);
+        index.update(Category::Artist, &[item("ar-1", "Miles Davis")]);
+        index.update_songs(&[
+            (item("1", "So What"), info("Miles Davis", item("al-1", "Kind of Blue"))),
+            (item("2", "Blue Train"), info("John Coltrane", item("al-2", "Blue Train"))),
+        ]);
+
+        fn search(index: &SearchIndex, text: &str) -> SearchResults {
+            index.search(&SearchQuery::parse(text))
+        }
+        let results = search(&index, "artist:davis");
+        assert_eq!(vec!["So What"], names(results.songs));
+        assert_eq!(vec!["Kind of Blue"], names(results.albums));
+        assert_eq!(vec!["Miles Davis"], names(results.artists));
+        assert_eq!(vec!["Blue Train"], names(search(&index, "blue artist:coltrane").songs));
+        assert_eq!(vec!["So What"], names(search(&index, "album:\"kind of\"").songs));
+        assert!(search(&index, "title:what artist:coltrane").songs.is_empty());
+
+        // Filters fold like the free text does
+        index.update(Category::Album, &[item("al-3", "Homogenic")]);
+        index.update_songs(&[(item("3", "Jóga"), info("Björk", item("al-3", "Homogenic")))]);
+        assert_eq!(vec!["Jóga"], names(search(&index, "artist:bjork").songs));
+        assert_eq!(vec!["Homogenic"], names(search(&index, "artist:BJORK").albums));
+
+        // Lengths are in characters, so one typo in a word outside ASCII is still one typo
+        index.update(Category::Artist, &[item("ar-2", "Мумий Тролль")]);
+        assert_eq!(vec!["Мумий Тролль"], names(search(&index, "троль").artists));
+    }
+}
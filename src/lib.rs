@@ -1,17 +1,66 @@
 use core::f32;
-use std::{collections::VecDeque, io::{self, BufRead, BufReader, Write}, time::Duration}; 
-use interprocess::local_socket::{prelude::*, GenericNamespaced, ListenerOptions, Stream, ToNsName};
+use std::{collections::VecDeque, io::{self, BufRead, BufReader, Write}, path::{Path, PathBuf}, sync::atomic::{AtomicU8, Ordering}, time::{Duration, Instant}}; 
+use interprocess::local_socket::{prelude::*, GenericNamespaced, ListenerNonblockingMode, ListenerOptions, Stream, ToNsName};
 use serde::{Deserialize, Serialize};
 use checksum_dir::checksum;
 
+pub mod playlist;
+pub use playlist::SyncResult;
+pub mod playlist_file;
+pub use playlist_file::{ImportResult, PlaylistFormat};
+pub mod smart;
+pub mod subsonic;
+pub mod scrobble;
+pub mod query;
+pub use query::{LibraryFilter, LibraryQuery, SortDirection, SortField};
+pub mod search;
+pub use search::{SearchLimits, SearchQuery, SearchResults};
+pub mod index;
+pub mod cover;
+pub use cover::CoverArt;
+pub mod lyrics;
+pub use lyrics::Lyrics;
+pub mod radio;
+pub use radio::{RadioStation, StreamMetadata};
+pub mod podcast;
+pub use podcast::{PodcastChannel, PodcastEpisode};
+pub mod bookmark;
+pub use bookmark::Bookmark;
+pub mod state;
+pub use state::PlaybackState;
+pub mod profile;
+pub use profile::Profile;
+pub mod credentials;
+pub use credentials::Secret;
+pub mod config;
+pub use config::{Config, LogLevel, Transcoding};
+pub mod playback;
+pub mod replaygain;
+pub use replaygain::{ReplayGain, ReplayGainMode};
+pub mod eq;
+pub use eq::{Band, BandKind};
+pub mod stretch;
+
 #[derive(Debug)]
 pub enum SlibError {
     InvalidCommand(u8),
     InvalidServerHash(Vec<u8>),
+    InvalidPlaylistFile(String),
+    InvalidRule(String),
+    Io(io::Error),
+    /// The Subsonic server couldn't be reached or sent something unexpected
+    Request(String),
+    /// The Subsonic server returned an error code and message
+    ServerError(u32, String),
+    /// The line of the config file and what is wrong with it
+    InvalidConfig(usize, String),
 }
 
 const NAME: &str = "slib.socket";
 
+/// How long the listener waits between looking for connections, which is also how late a save can be
+const ACCEPT_POLL: Duration = Duration::from_millis(20);
+
 const HASH: [u8; 32] = checksum!("./src");
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -21,14 +70,22 @@ pub enum  Commands {
     /// Shutdown the server
     Shutdown,
     
-    /// Return all artists
-    FetchArtists,
-    /// Return all albums
-    FetchAlbums,
+    /// Return the artists matching a query
+    FetchArtists(LibraryQuery),
+    /// Return the albums matching a query
+    FetchAlbums(LibraryQuery),
     /// Return all playlists
     FetchPlaylists,
-    /// Return all songs
-    FetchSongs,
+    /// Return the songs matching a query
+    FetchSongs(LibraryQuery),
+    /// Return a query in chunks, one line each, ending with an empty chunk
+    StreamLibrary{library: Library, query: LibraryQuery, chunk: usize},
+    /// Return all genres with how much is in them
+    FetchGenres,
+    /// Return a page of the albums in a genre
+    FetchByGenre{genre: String, offset: usize, limit: Option<usize>},
+    /// Return the albums from a range of years, newest first if `from` is after `to`
+    FetchByYear{from: u16, to: u16},
 
     /// Tell the Subsonic server to rescan
     Scan,
@@ -46,8 +103,9 @@ pub enum  Commands {
     /// Skip the currentlly playing song
     Skip,
 
-    /// Add a song to the queue
-    QueueAdd{id: Item, position: u8},
+    /// Add a song or radio station to the queue, starting from its bookmark when auto resume is on.
+    /// A quality streams this song differently from the rest.
+    QueueAdd{id: Item, position: u8, #[serde(default)] quality: Option<Transcoding>},
     /// Remove a song from the queue
     QueueRemove(u8),
 
@@ -57,45 +115,138 @@ pub enum  Commands {
     VolumeSet(f32),
 
     /// Search for a query
-    Search(String),
-    /// Download a song for offline playback
-    Download(Item),
+    Search(SearchQuery),
+    /// Download a song or podcast episode for offline playback, at the stream quality if none is given
+    Download{id: Item, #[serde(default)] quality: Option<Transcoding>},
     /// Delete a song from offline playback
     Delete(Item),
     /// Favorite a song on the Subsonic server
     Star(Item),
-
-    /// Download all the songs from a playlist
+    /// Remove a favorite on the Subsonic server
+    Unstar(Item),
+    /// Rate a song from 1 to 5, 0 removes the rating
+    SetRating{id: Item, rating: u8},
+    /// Return all favorites
+    FetchStarred,
+
+    /// Merge server changes into a local playlist and download its songs
     PlaylistDownload(Item),
-    /// Upload changes on a local playlist
+    /// Merge and upload changes on a local playlist
     PlaylistUpload(Item),
     /// Create a new local playlist
     PlaylistNew{name: String},
-    /// Add to a local playlist
-    PlaylistAddTo{playlist: Item, id: Item},
+    /// Create a smart playlist from a rule like `genre = Jazz AND year < 1970, order by random, limit 50`
+    SmartPlaylistNew{name: String, rule: String},
+    /// Re-evaluate the rule of a smart playlist
+    PlaylistRefresh(Item),
+    /// Add to a local playlist, at the end if there is no position
+    PlaylistAddTo{playlist: Item, id: Item, position: Option<usize>},
     /// Remove from a local playlist
     PlaylistRemoveFrom{playlist: Item, id: Item},
+    /// Remove the song at an index from a local playlist
+    PlaylistRemoveAt{playlist: Item, index: usize},
+    /// Move a song within a local playlist
+    PlaylistMove{playlist: Item, from: usize, to: usize},
+    /// Rename a local playlist
+    PlaylistRename{playlist: Item, name: String},
+    /// Set the comment on a local playlist
+    PlaylistSetComment{playlist: Item, comment: String},
+    /// Set whether a local playlist is public
+    PlaylistSetPublic{playlist: Item, public: bool},
     /// Delete a local playlist
     PlaylistDelete(Item),
+    /// Create a local playlist from a playlist file
+    PlaylistImport{format: PlaylistFormat, data: String},
+    /// Write a local playlist out as a playlist file
+    PlaylistExport{playlist: Item, format: PlaylistFormat},
 
     /// Get the info of a song
     SongInfo(Item),
     /// Get the info of a album
     AlbumInfo(Item),
+    /// Get the cover art of an item, scaled to a size in pixels, as bytes if inline
+    CoverArt{id: Item, size: Option<u32>, inline: bool},
+    /// Get the lyrics of a song, synced if there are timestamps
+    Lyrics(Item),
+
+    /// Return all internet radio stations
+    FetchRadioStations,
+    /// Add an internet radio station on the Subsonic server
+    RadioCreate{name: String, stream_url: String, homepage_url: Option<String>},
+    /// Remove an internet radio station from the Subsonic server
+    RadioDelete(Item),
+
+    /// Return all podcast channels
+    FetchPodcasts,
+    /// Return the episodes of a podcast channel, with where to resume them
+    FetchEpisodes(Item),
+    /// Subscribe to a podcast by feed url
+    PodcastSubscribe{url: String},
+    /// Unsubscribe from a podcast channel
+    PodcastUnsubscribe(Item),
+    /// Mark a podcast episode played or unplayed
+    PodcastMarkPlayed{episode: Item, played: bool},
+
+    /// Save where to resume an item, replacing its old bookmark
+    BookmarkSet{item: Item, position: Duration, comment: Option<String>},
+    /// Remove the bookmark of an item
+    BookmarkDelete(Item),
+    /// Return all bookmarks
+    FetchBookmarks,
+    /// Set whether queueing a bookmarked item resumes it
+    BookmarkAutoResume(bool),
+
+    /// Save the queue to the Subsonic server, to carry on from another machine
+    PlayQueueUpload,
+    /// Take the queue saved on the Subsonic server
+    PlayQueueDownload,
+
+    /// Return the server profiles
+    ProfileList,
+    /// Add a server profile
+    ProfileAdd(Profile),
+    /// Remove a server profile other than the active one
+    ProfileRemove{name: String},
+    /// Switch to another server profile, along with its cache and playlists
+    ProfileSwitch{name: String},
+
+    /// Check a login against the server and keep the password encrypted, it is never sent back
+    Login{url: String, user: String, password: Secret},
+
+    /// Read the config file again, also done on SIGHUP
+    ReloadConfig,
+
+    /// Set the stream quality of the active profile, unset fields fall back to the config
+    SetStreamQuality(Transcoding),
+    /// Set how long tracks overlap, zero plays them back to back with no gap
+    SetCrossfade(Duration),
+    /// Set the ReplayGain mode, preamp and clipping prevention, applied along with the volume
+    SetReplayGain(ReplayGain),
+
+    /// Set the equalizer bands, no bands turns it off
+    EqSet(Vec<Band>),
+    /// Save the current equalizer bands as a preset
+    EqPresetSave{name: String},
+    /// Set the equalizer to a saved or built in preset: flat, bass boost or voice
+    EqPresetLoad{name: String},
+    /// Set the playback speed from 0.5 to 3, keeping the pitch
+    SetPlaybackRate(f32),
 }
 
 
 
 pub trait Daemon {
     fn shutdown(&self)                                              -> bool;
-    /// Return all artists
-    fn fetch_artists(&mut self)                                     -> Vec<Item>;
-    /// Return all albums
-    fn fetch_albums(&mut self)                                      -> Vec<Item>;
-    /// Return all songs
+    /// Return the artists matching a query, sorted by the daemon
+    fn fetch_artists(&mut self, query: LibraryQuery)                -> Vec<Item>;
+    /// Return the albums matching a query, sorted by the daemon
+    fn fetch_albums(&mut self, query: LibraryQuery)                 -> Vec<Item>;
+    /// Return all playlists, re-evaluating smart playlists
     fn fetch_playlists(&mut self)                                   -> Vec<Item>;
-    /// Return all songs
-    fn fetch_songs(&mut self)                                       -> Vec<Item>;
+    /// Return the songs matching a query, sorted by the daemon
+    fn fetch_songs(&mut self, query: LibraryQuery)                  -> Vec<Item>;
+    /// Return all genres with how much is in them
+    fn fetch_genres(&mut self)                                      -> Vec<Genre>;
     /// Tell the Subsonic server to rescan
     fn scan(&mut self)                                              -> bool;
     /// Get the status of playback
@@ -110,55 +261,224 @@ pub trait Daemon {
     fn pause(&mut self)                                             -> bool;
     /// Skip the currentlly playing song
     fn skip(&mut self)                                              -> bool;
-    /// Add a song to the queue
-    fn queue_add(&mut self, id: Item, position: u8)                 -> bool;
+    /// Add a song or radio station to the queue, starting from its bookmark when auto resume is on
+    fn queue_add(&mut self, id: Item, position: u8, quality: Option<Transcoding>) -> bool;
     /// Remove a song from the queue
     fn queue_remove(&mut self, index: u8)                           -> bool;
     /// Adjust volume by percent
     fn volume_adjust(&mut self, amount: f32)                        -> bool;
     /// Set the volume by percent
     fn volume_set(&mut self, amount: f32)                           -> bool;
-    /// Search for a query
-    fn search(&self, query: String)                                 -> Vec<Item>;
-    /// Download a song for offline playback
-    fn download(&self, id: Item)                                    -> bool;
+    /// Search for a query, only in the local index if the query is local
+    fn search(&self, query: SearchQuery)                            -> SearchResults;
+    /// Download a song or podcast episode for offline playback, at the stream quality if none is given
+    fn download(&self, id: Item, quality: Option<Transcoding>)      -> bool;
     /// Delete a song from offline playback
     fn delete(&self, id: Item)                                      -> bool;
     /// Favorite a song on the Subsonic server
     fn star(&self, id: Item)                                        -> bool; 
-    /// Download all the songs from a playlist
-    fn playlist_download(&self, id: Item)                           -> bool;
-    /// Upload changes on a local playlist
-    fn playlist_upload(&self, id: Item)                             -> bool;
+    /// Remove a favorite on the Subsonic server
+    fn unstar(&self, id: Item)                                      -> bool;
+    /// Rate a song from 1 to 5, 0 removes the rating. Anything over 5 is refused without calling this.
+    fn set_rating(&self, id: Item, rating: u8)                      -> bool;
+    /// Return all favorites
+    fn fetch_starred(&mut self)                                     -> Starred;
+    /// Merge server changes into a local playlist and download its songs
+    fn playlist_download(&self, id: Item)                           -> SyncResult;
+    /// Merge and upload changes on a local playlist
+    fn playlist_upload(&self, id: Item)                             -> SyncResult;
     /// Create a new local playlist
     fn playlist_new(&self, name: String)                            -> bool;
-    /// Add to a local playlist
-    fn playlist_add_to(&self, playlist: Item, id: Item)             -> bool;
+    /// Create a smart playlist from a rule
+    fn smart_playlist_new(&self, name: String, rule: String)        -> bool;
+    /// Re-evaluate the rule of a smart playlist
+    fn playlist_refresh(&self, id: Item)                            -> bool;
+    /// Add to a local playlist, at the end if there is no position
+    fn playlist_add_to(&self, playlist: Item, id: Item, position: Option<usize>) -> bool;
     /// Remove from a local playlist
     fn playlist_remove_from(&self, playlist: Item, id: Item)        -> bool;
+    /// Remove the song at an index from a local playlist
+    fn playlist_remove_at(&self, playlist: Item, index: usize)      -> bool;
+    /// Move a song within a local playlist
+    fn playlist_move(&self, playlist: Item, from: usize, to: usize) -> bool;
+    /// Rename a local playlist
+    fn playlist_rename(&self, playlist: Item, name: String)         -> bool;
+    /// Set the comment on a local playlist
+    fn playlist_set_comment(&self, playlist: Item, comment: String) -> bool;
+    /// Set whether a local playlist is public
+    fn playlist_set_public(&self, playlist: Item, public: bool)     -> bool;
     /// Delete a local playlist
     fn playlist_delete(&self, id: Item)                             -> bool;
+    /// Create a local playlist from a playlist file
+    fn playlist_import(&self, format: PlaylistFormat, data: String) -> Option<ImportResult>;
+    /// Write a local playlist out as a playlist file
+    fn playlist_export(&self, playlist: Item, format: PlaylistFormat) -> Option<String>;
     /// Get the info of a song
     fn song_info(&self, id: Item)                                   -> Option<SongInfo>;
     /// Get the info of a album
     fn album_info(&self, id: Item)                                  -> Option<AlbumInfo>;
+    /// Get the cover art of an item, scaled to a size in pixels, as bytes if inline
+    fn cover_art(&self, id: Item, size: Option<u32>, inline: bool)  -> Option<CoverArt>;
+    /// Get the lyrics of a song, synced if there are timestamps
+    fn lyrics(&self, id: Item)                                      -> Option<Lyrics>;
+    /// Return all internet radio stations
+    fn fetch_radio_stations(&mut self)                              -> Vec<RadioStation>;
+    /// Add an internet radio station on the Subsonic server
+    fn radio_create(&self, name: String, stream_url: String, homepage_url: Option<String>) -> bool;
+    /// Remove an internet radio station from the Subsonic server
+    fn radio_delete(&self, id: Item)                                -> bool;
+    /// Return all podcast channels
+    fn fetch_podcasts(&mut self)                                    -> Vec<PodcastChannel>;
+    /// Return the episodes of a podcast channel, with where to resume them
+    fn fetch_episodes(&mut self, channel: Item)                     -> Vec<PodcastEpisode>;
+    /// Subscribe to a podcast by feed url
+    fn podcast_subscribe(&self, url: String)                        -> bool;
+    /// Unsubscribe from a podcast channel
+    fn podcast_unsubscribe(&self, channel: Item)                    -> bool;
+    /// Mark a podcast episode played or unplayed
+    fn podcast_mark_played(&mut self, episode: Item, played: bool)  -> bool;
+    /// Save where to resume an item, replacing its old bookmark
+    fn bookmark_set(&mut self, item: Item, position: Duration, comment: Option<String>) -> bool;
+    /// Remove the bookmark of an item
+    fn bookmark_delete(&mut self, item: Item)                       -> bool;
+    /// Return all bookmarks
+    fn fetch_bookmarks(&mut self)                                   -> Vec<Bookmark>;
+    /// Set whether queueing a bookmarked item resumes it
+    fn bookmark_auto_resume(&mut self, enabled: bool)               -> bool;
+    /// Save the queue to the Subsonic server, to carry on from another machine
+    fn play_queue_upload(&self)                                     -> bool;
+    /// Take the queue saved on the Subsonic server
+    fn play_queue_download(&mut self)                               -> bool;
+    /// Put back a saved queue, song, position and volume without starting playback
+    fn restore(&mut self, state: PlaybackState)                     -> bool;
+    /// Return the server profiles
+    fn profile_list(&self)                                          -> Vec<Profile>;
+    /// Add a server profile
+    fn profile_add(&mut self, profile: Profile)                     -> bool;
+    /// Remove a server profile other than the active one
+    fn profile_remove(&mut self, name: String)                      -> bool;
+    /// Switch to another server profile, along with its cache and playlists
+    fn profile_switch(&mut self, name: String)                      -> bool;
+    /// Check a login against the server and keep the password encrypted, it is never sent back
+    fn login(&mut self, url: String, user: String, password: Secret) -> bool;
+    /// Take on a config, at start and on every reload. Returns false to keep the old one.
+    fn configure(&mut self, config: Config)                         -> bool;
+    /// Set the stream quality of the active profile, unset fields fall back to the config
+    fn set_stream_quality(&mut self, quality: Transcoding)          -> bool;
+    /// Set how long tracks overlap, zero plays them back to back with no gap
+    fn set_crossfade(&mut self, crossfade: Duration)                -> bool;
+    /// Set the ReplayGain mode, preamp and clipping prevention, applied along with the volume
+    fn set_replay_gain(&mut self, replay_gain: ReplayGain)          -> bool;
+    /// Set the equalizer bands, no bands turns it off
+    fn eq_set(&mut self, bands: Vec<Band>)                          -> bool;
+    /// Save the current equalizer bands as a preset
+    fn eq_preset_save(&mut self, name: String)                      -> bool;
+    /// Set the equalizer to a saved or built in preset: flat, bass boost or voice
+    fn eq_preset_load(&mut self, name: String)                      -> bool;
+    /// Set the playback speed from 0.5 to 3, keeping the pitch
+    fn set_playback_rate(&mut self, rate: f32)                      -> bool;
+
+    /// The id to queue or download an item by, before `queue_add` and `download` see it. A podcast
+    /// episode streams by its `stream_id`, see `PodcastProgress::media_id`. None refuses the item.
+    fn media_id(&self, id: &str) -> Option<String> {
+        Some(id.to_string())
+    }
+
+    /// Where the config file is, None to run without one
+    fn config_path(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// Read the config file again and hand it to `configure`
+    fn reload_config(&mut self) -> Result<(), String> {
+        let path = self.config_path().ok_or_else(|| String::from("there is no config file"))?;
+        let config = Config::load(&path).map_err(|e| config_error(&path, e))?;
+        let log_level = config.log_level;
+        match self.configure(config) {
+            true => {
+                set_log_level(log_level);
+                Ok(())
+            },
+            false => Err(String::from("the daemon kept its old config")),
+        }
+    }
+
+    /// Where to keep the playback state across restarts, None to not keep it
+    fn state_path(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// Write the playback state to `state_path`. `start` calls this on shutdown and about every
+    /// `state::SAVE_INTERVAL` even while idle, a player thread may call it more often.
+    fn save_state(&self) -> io::Result<()> {
+        match self.state_path() {
+            Some(path) => PlaybackState::from_status(self.status()).save(&path),
+            None => Ok(()),
+        }
+    }
 
 
     fn start(&mut self) 
     { 
+        // The config has to be good to start, after that a bad reload keeps the old one
+        let socket_name = match self.config_path() {
+            Some(path) => match Config::load(&path) {
+                Ok(config) => {
+                    let socket_name = config.socket_name.clone();
+                    set_log_level(config.log_level);
+                    self.configure(config);
+                    socket_name
+                },
+                Err(e) => {
+                    log(LogLevel::Error, format_args!("Not starting, {}", config_error(&path, e)));
+                    return;
+                },
+            },
+            None => NAME.to_string(),
+        };
+
         //  Try to put the name in the Namespace
-        let name = NAME.to_ns_name::<GenericNamespaced>().unwrap();
+        let name = socket_name.as_str().to_ns_name::<GenericNamespaced>().unwrap();
 
-        // Create our local socket listener using the name
-        let opts = ListenerOptions::new().name(name);
+        // Create our local socket listener using the name. Accepting doesn't block,
+        // so the state still gets saved on time while no client is connecting.
+        let opts = ListenerOptions::new().name(name).nonblocking(ListenerNonblockingMode::Accept);
         let listener = opts.create_sync().unwrap();
 
+        // Only once there is a socket to send the reload to
+        if self.config_path().is_some() {
+            watch_sighup(socket_name);
+        }
+
         // Create a buffer we can write our input into. The size may need to be changed
         let mut buffer = String::with_capacity(128);
 
-        // Infinite Iterator over the connections incoming in from the listener
-        'listen: for conn in listener.incoming().filter_map(handle_error)
+        // Pick up where the last run left off
+        if let Some(state) = self.state_path().and_then(|path| PlaybackState::load(&path).ok().flatten()) {
+            self.restore(state);
+        }
+        let mut last_saved = Instant::now();
+
+        // Loop over the connections incoming in from the listener
+        'listen: loop
         {
+            if last_saved.elapsed() >= state::SAVE_INTERVAL {
+                let _ = self.save_state();
+                last_saved = Instant::now();
+            }
+
+            let conn = match listener.accept() {
+                Ok(conn) => conn,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(ACCEPT_POLL);
+                    continue;
+                },
+                Err(e) => {
+                    log(LogLevel::Warn, format_args!("Incomming connection failed: {e}"));
+                    continue;
+                },
+            };
+
             // Make a reader for the connection
             let mut conn = BufReader::new(conn);
             // Read from the connection
@@ -171,11 +491,18 @@ pub trait Daemon {
             let command = serde_json::from_str::<Commands>(&buffer).unwrap();
 
 
+            // Streamed fetches write their own lines
+            if let Commands::StreamLibrary{library, query, chunk} = command {
+                self.stream_library(library, query, chunk, conn.get_mut());
+                buffer.clear();
+                continue;
+            }
+
             // Get the response from the Daemon
             let response = self.interpert_command(command);
 
             // Send the response back
-            conn.get_mut().write_all(&response.as_bytes()).expect("failed to send");
+            conn.get_mut().write_all(response.as_bytes()).expect("failed to send");
             conn.get_mut().write_all(b"\n").expect("failed to send");
 
             // Turn the command back into an enum again
@@ -192,6 +519,7 @@ pub trait Daemon {
                     // and the daemon is good to stop
                     response == serde_json::to_string(&t).unwrap()
                     {
+                        let _ = self.save_state();
                         break 'listen;
                     }
         }
@@ -199,14 +527,70 @@ pub trait Daemon {
     }
 
 
+    /// Run a query against one of the libraries
+    fn fetch_library(&mut self, library: Library, query: LibraryQuery) -> Vec<Item> {
+        match library {
+            Library::Artists => self.fetch_artists(query),
+            Library::Albums  => self.fetch_albums(query),
+            Library::Songs   => self.fetch_songs(query),
+        }
+    }
+
+    /// Return a page of the albums in a genre
+    fn fetch_by_genre(&mut self, genre: String, offset: usize, limit: Option<usize>) -> Vec<Item> {
+        self.fetch_albums(LibraryQuery{offset, limit, ..LibraryQuery::by_genre(genre)})
+    }
+
+    /// Return the albums from a range of years, newest first if `from` is after `to`
+    fn fetch_by_year(&mut self, from: u16, to: u16) -> Vec<Item> {
+        self.fetch_albums(LibraryQuery::by_year(from, to))
+    }
+
+    /// Write a query out a chunk at a time, followed by an empty chunk
+    fn stream_library(&mut self, library: Library, query: LibraryQuery, chunk: usize, out: &mut dyn Write) {
+        // Every chunk of a random sort has to come from the same shuffle
+        let query = query.seeded();
+        let chunk = chunk.max(1);
+        let mut offset = query.offset;
+        // Offsets and limits come from clients, so none of this may overflow
+        let end = query.limit.map(|l| query.offset.saturating_add(l));
+        loop {
+            let limit = end.map_or(chunk, |end| chunk.min(end - offset));
+            let items = match limit {
+                0 => Vec::new(),
+                _ => self.fetch_library(library, LibraryQuery{offset, limit: Some(limit), ..query.clone()}),
+            };
+
+            let line = serde_json::to_string(&items).unwrap();
+            if out.write_all(line.as_bytes()).and_then(|_| out.write_all(b"\n")).is_err() {
+                // The client went away
+                return;
+            }
+
+            // An empty chunk ends the stream, a short one means the next would be empty
+            if items.is_empty() {
+                return;
+            }
+            if items.len() < chunk || end == Some(offset.saturating_add(items.len())) {
+                let _ = out.write_all(b"[]\n");
+                return;
+            }
+            offset = offset.saturating_add(chunk);
+        }
+    }
+
     fn interpert_command(&mut self, c: Commands) -> String {
         match c {
                 Commands::Verify                           => { serde_json::to_string( &HASH.to_vec()                           ) },
                 Commands::Shutdown                         => { serde_json::to_string( &self.shutdown()                         ) },
-                Commands::FetchArtists                     => { serde_json::to_string( &self.fetch_artists()                    ) },
-                Commands::FetchAlbums                      => { serde_json::to_string( &self.fetch_albums()                     ) },
+                Commands::FetchArtists(query)              => { serde_json::to_string( &self.fetch_artists(query)               ) },
+                Commands::FetchAlbums(query)               => { serde_json::to_string( &self.fetch_albums(query)                ) },
                 Commands::FetchPlaylists                   => { serde_json::to_string( &self.fetch_playlists()                  ) },
-                Commands::FetchSongs                       => { serde_json::to_string( &self.fetch_songs()                      ) },
+                Commands::FetchSongs(query)                => { serde_json::to_string( &self.fetch_songs(query)                 ) },
+                Commands::StreamLibrary{library, query, chunk: _} => { serde_json::to_string( &self.fetch_library(library, query) ) },
+                Commands::FetchGenres                      => { serde_json::to_string( &self.fetch_genres()                     ) },
+                Commands::FetchByGenre{genre, offset, limit} => { serde_json::to_string( &self.fetch_by_genre(genre, offset, limit) ) },
+                Commands::FetchByYear{from, to}            => { serde_json::to_string( &self.fetch_by_year(from, to)            ) },
                 Commands::Scan                             => { serde_json::to_string( &self.scan()                             ) },
                 Commands::Status                           => { serde_json::to_string( &self.status()                           ) },
                 Commands::Restart                          => { serde_json::to_string( &self.restart()                          ) },
@@ -214,44 +598,129 @@ pub trait Daemon {
                 Commands::Stop                             => { serde_json::to_string( &self.stop()                             ) },
                 Commands::Pause                            => { serde_json::to_string( &self.pause()                            ) },
                 Commands::Skip                             => { serde_json::to_string( &self.skip()                             ) },
-                Commands::QueueAdd{id, position}           => { serde_json::to_string( &self.queue_add(id, position)            ) },
+                Commands::QueueAdd{id, position, quality}  => { serde_json::to_string( &media_item(self, id).is_some_and(|id| self.queue_add(id, position, quality)) ) },
                 Commands::QueueRemove(index)               => { serde_json::to_string( &self.queue_remove(index)                ) },
                 Commands::VolumeAdjust(amount)             => { serde_json::to_string( &self.volume_adjust(amount)              ) },
                 Commands::VolumeSet(amount)                => { serde_json::to_string( &self.volume_set(amount)                 ) },
                 Commands::Search(query)                    => { serde_json::to_string( &self.search(query)                      ) },
-                Commands::Download(id)                     => { serde_json::to_string( &self.download(id)                       ) },
+                Commands::Download{id, quality}            => { serde_json::to_string( &media_item(self, id).is_some_and(|id| self.download(id, quality)) ) },
                 Commands::Delete(id)                       => { serde_json::to_string( &self.delete(id)                         ) },
                 Commands::Star(id)                         => { serde_json::to_string( &self.star(id)                           ) },
+                Commands::Unstar(id)                       => { serde_json::to_string( &self.unstar(id)                         ) },
+                Commands::SetRating{id, rating}            => { serde_json::to_string( &(rating <= 5 && self.set_rating(id, rating)) ) },
+                Commands::FetchStarred                     => { serde_json::to_string( &self.fetch_starred()                    ) },
                 Commands::PlaylistDownload(id)             => { serde_json::to_string( &self.playlist_download(id)              ) },
                 Commands::PlaylistUpload(id)               => { serde_json::to_string( &self.playlist_upload(id)                ) },
                 Commands::PlaylistNew{name}                => { serde_json::to_string( &self.playlist_new(name)                 ) },
-                Commands::PlaylistAddTo{playlist, id}      => { serde_json::to_string( &self.playlist_add_to(playlist, id)      ) },
+                Commands::SmartPlaylistNew{name, rule}     => { serde_json::to_string( &self.smart_playlist_new(name, rule)     ) },
+                Commands::PlaylistRefresh(id)              => { serde_json::to_string( &self.playlist_refresh(id)               ) },
+                Commands::PlaylistAddTo{playlist, id, position} => { serde_json::to_string( &self.playlist_add_to(playlist, id, position) ) },
                 Commands::PlaylistRemoveFrom{playlist, id} => { serde_json::to_string( &self.playlist_remove_from(playlist, id) ) },
+                Commands::PlaylistRemoveAt{playlist, index} => { serde_json::to_string( &self.playlist_remove_at(playlist, index) ) },
+                Commands::PlaylistMove{playlist, from, to} => { serde_json::to_string( &self.playlist_move(playlist, from, to)  ) },
+                Commands::PlaylistRename{playlist, name}   => { serde_json::to_string( &self.playlist_rename(playlist, name)    ) },
+                Commands::PlaylistSetComment{playlist, comment} => { serde_json::to_string( &self.playlist_set_comment(playlist, comment) ) },
+                Commands::PlaylistSetPublic{playlist, public}   => { serde_json::to_string( &self.playlist_set_public(playlist, public)   ) },
                 Commands::PlaylistDelete(id)               => { serde_json::to_string( &self.playlist_delete(id)                ) },
+                Commands::PlaylistImport{format, data}     => { serde_json::to_string( &self.playlist_import(format, data)      ) },
+                Commands::PlaylistExport{playlist, format} => { serde_json::to_string( &self.playlist_export(playlist, format)  ) },
                 Commands::SongInfo(id)                     => { serde_json::to_string( &self.song_info(id)                      ) },
                 Commands::AlbumInfo(id)                    => { serde_json::to_string( &self.album_info(id)                     ) },
+                Commands::CoverArt{id, size, inline}       => { serde_json::to_string( &self.cover_art(id, size, inline)        ) },
+                Commands::Lyrics(id)                       => { serde_json::to_string( &self.lyrics(id)                         ) },
+                Commands::FetchRadioStations               => { serde_json::to_string( &self.fetch_radio_stations()             ) },
+                Commands::RadioCreate{name, stream_url, homepage_url} => { serde_json::to_string( &self.radio_create(name, stream_url, homepage_url) ) },
+                Commands::RadioDelete(id)                  => { serde_json::to_string( &self.radio_delete(id)                   ) },
+                Commands::FetchPodcasts                    => { serde_json::to_string( &self.fetch_podcasts()                   ) },
+                Commands::FetchEpisodes(channel)           => { serde_json::to_string( &self.fetch_episodes(channel)            ) },
+                Commands::PodcastSubscribe{url}            => { serde_json::to_string( &self.podcast_subscribe(url)             ) },
+                Commands::PodcastUnsubscribe(channel)      => { serde_json::to_string( &self.podcast_unsubscribe(channel)       ) },
+                Commands::PodcastMarkPlayed{episode, played} => { serde_json::to_string( &self.podcast_mark_played(episode, played) ) },
+                Commands::BookmarkSet{item, position, comment} => { serde_json::to_string( &self.bookmark_set(item, position, comment) ) },
+                Commands::BookmarkDelete(item)             => { serde_json::to_string( &self.bookmark_delete(item)              ) },
+                Commands::FetchBookmarks                   => { serde_json::to_string( &self.fetch_bookmarks()                  ) },
+                Commands::BookmarkAutoResume(enabled)      => { serde_json::to_string( &self.bookmark_auto_resume(enabled)      ) },
+                Commands::PlayQueueUpload                  => { serde_json::to_string( &self.play_queue_upload()                ) },
+                Commands::PlayQueueDownload                => { serde_json::to_string( &self.play_queue_download()              ) },
+                Commands::ProfileList                      => { serde_json::to_string( &self.profile_list()                     ) },
+                Commands::ProfileAdd(profile)              => { serde_json::to_string( &self.profile_add(profile)               ) },
+                Commands::ProfileRemove{name}              => { serde_json::to_string( &self.profile_remove(name)               ) },
+                Commands::ProfileSwitch{name}              => { serde_json::to_string( &self.profile_switch(name)               ) },
+                Commands::Login{url, user, password}       => { serde_json::to_string( &self.login(url, user, password)         ) },
+                Commands::ReloadConfig                     => { serde_json::to_string( &self.reload_config()                    ) },
+                Commands::SetStreamQuality(quality)        => { serde_json::to_string( &self.set_stream_quality(quality)        ) },
+                Commands::SetCrossfade(crossfade)          => { serde_json::to_string( &self.set_crossfade(crossfade)           ) },
+                Commands::SetReplayGain(replay_gain)       => { serde_json::to_string( &self.set_replay_gain(replay_gain)       ) },
+                Commands::EqSet(bands)                     => { serde_json::to_string( &self.eq_set(bands)                      ) },
+                Commands::EqPresetSave{name}               => { serde_json::to_string( &self.eq_preset_save(name)               ) },
+                Commands::EqPresetLoad{name}               => { serde_json::to_string( &self.eq_preset_load(name)               ) },
+                Commands::SetPlaybackRate(rate)            => { serde_json::to_string( &self.set_playback_rate(rate)            ) },
             }.unwrap()
     }
 }
 
-fn handle_error(conn: io::Result<Stream>) -> Option<Stream> {
-    match conn {
-        Ok(c) => Some(c),
-        Err(e) => {
-            eprintln!("Incomming connection failed: {e}");
-            None
-        }
+/// An item with the id it streams by, None if the daemon refuses it
+fn media_item<D: Daemon + ?Sized>(daemon: &D, item: Item) -> Option<Item> {
+    let id = daemon.media_id(&item.id)?;
+    Some(Item { id, ..item })
+}
+
+/// The most detailed messages `log` prints, from the config's `log_level`
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Print a message to stderr, unless it is more detailed than the configured log level
+pub fn log(level: LogLevel, message: impl std::fmt::Display) {
+    if level as u8 <= LOG_LEVEL.load(Ordering::Relaxed) {
+        eprintln!("{level:?}: {message}");
     }
 }
 
-pub struct Client;
+/// A config error as `path:line: message`
+fn config_error(path: &Path, e: SlibError) -> String {
+    match e {
+        SlibError::InvalidConfig(line, message) => format!("{}:{line}: {message}", path.display()),
+        e => format!("{}: {e:?}", path.display()),
+    }
+}
+
+/// Reload the config on SIGHUP, by sending `ReloadConfig` to our own socket
+#[cfg(unix)]
+fn watch_sighup(socket_name: String) {
+    let Ok(mut signals) = signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP]) else { return };
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            match Client::connect(&socket_name).map(|client| client.reload_config()) {
+                Ok(Err(e)) => log(LogLevel::Error, format_args!("Config reload failed: {e}")),
+                Err(e) => log(LogLevel::Error, format_args!("Config reload failed: {e:?}")),
+                Ok(Ok(())) => {},
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn watch_sighup(_socket_name: String) {}
+
+pub struct Client {
+    name: String,
+}
 impl Client {
     pub fn new() -> Result<Client,SlibError> 
+    {
+        Client::connect(NAME)
+    }
+
+    /// Connect to a daemon listening on another socket name, as set in its config
+    pub fn connect(name: &str) -> Result<Client,SlibError> 
     {
         let command = Commands::Verify;
 
         let mut buffer = String::with_capacity(128);
-        let conn = Stream::connect(NAME.to_ns_name::<GenericNamespaced>().unwrap()).unwrap();
+        let conn = name.to_ns_name::<GenericNamespaced>().and_then(Stream::connect).map_err(SlibError::Io)?;
         let mut conn = BufReader::new(conn);
         let _ = conn.get_mut().write_all(serde_json::to_string(&command).unwrap().as_bytes());
         let _ = conn.get_mut().write_all(b"\n");
@@ -262,11 +731,12 @@ impl Client {
         buffer.next_back();
         let buffer = buffer.as_str();
 
-        let hash = serde_json::from_str::<Vec<u8>>(&buffer).unwrap();
+        // Nothing back means whatever is on the socket isn't a daemon
+        let hash = serde_json::from_str::<Vec<u8>>(buffer).map_err(|_| SlibError::InvalidServerHash(Vec::new()))?;
         let matching = hash.iter().zip(HASH.to_vec().iter()).filter(|&(a, b)| a == b).count();
         if matching == hash.len() 
         {
-            Ok(Client{})
+            Ok(Client{name: name.to_string()})
         }
         else
         {
@@ -275,12 +745,17 @@ impl Client {
         
     }
 
-    fn send_command(&self, c: Commands) -> String {
-        let mut buffer = String::with_capacity(128);
-        let conn = Stream::connect(NAME.to_ns_name::<GenericNamespaced>().unwrap()).unwrap();
+    fn open(&self, c: Commands) -> BufReader<Stream> {
+        let conn = Stream::connect(self.name.as_str().to_ns_name::<GenericNamespaced>().unwrap()).unwrap();
         let mut conn = BufReader::new(conn);
         let _ = conn.get_mut().write_all(serde_json::to_string(&c).unwrap().as_bytes());
         let _ = conn.get_mut().write_all(b"\n");
+        conn
+    }
+
+    fn send_command(&self, c: Commands) -> String {
+        let mut buffer = String::with_capacity(128);
+        let mut conn = self.open(c);
         let _ = conn.read_line(&mut buffer);
         //
         // Remove newline from the end
@@ -297,14 +772,14 @@ impl Client {
         serde_json::from_str::<bool>(&self.send_command(Commands::Shutdown)).unwrap()
     }
     /// Fetch IDs of remote songs and playlists
-    pub fn fetch_artist(&self)                                                 -> Vec<Item>
+    pub fn fetch_artist(&self, query: LibraryQuery)                            -> Vec<Item>
     {
-        serde_json::from_str::<Vec<Item>>(&self.send_command(Commands::FetchArtists)).unwrap()
+        serde_json::from_str::<Vec<Item>>(&self.send_command(Commands::FetchArtists(query))).unwrap()
     }
     /// Fetch IDs of remote songs and playlists
-    pub fn fetch_albums(&self)                                                 -> Vec<Item>
+    pub fn fetch_albums(&self, query: LibraryQuery)                            -> Vec<Item>
     {
-        serde_json::from_str::<Vec<Item>>(&self.send_command(Commands::FetchAlbums)).unwrap()
+        serde_json::from_str::<Vec<Item>>(&self.send_command(Commands::FetchAlbums(query))).unwrap()
     }
     /// Fetch IDs of remote songs and playlists
     pub fn fetch_playlists(&self)                                                 -> Vec<Item>
@@ -312,9 +787,29 @@ impl Client {
         serde_json::from_str::<Vec<Item>>(&self.send_command(Commands::FetchPlaylists)).unwrap()
     }
     /// Fetch IDs of remote songs and playlists
-    pub fn fetch_songs(&self)                                                 -> Vec<Item>
+    pub fn fetch_songs(&self, query: LibraryQuery)                             -> Vec<Item>
+    {
+        serde_json::from_str::<Vec<Item>>(&self.send_command(Commands::FetchSongs(query))).unwrap()
+    }
+    /// Return all genres with how much is in them
+    pub fn fetch_genres(&self)                                                 -> Vec<Genre>
+    {
+        serde_json::from_str::<Vec<Genre>>(&self.send_command(Commands::FetchGenres)).unwrap()
+    }
+    /// Return a page of the albums in a genre
+    pub fn fetch_by_genre(&self, genre: String, offset: usize, limit: Option<usize>) -> Vec<Item>
     {
-        serde_json::from_str::<Vec<Item>>(&self.send_command(Commands::FetchSongs)).unwrap()
+        serde_json::from_str::<Vec<Item>>(&self.send_command(Commands::FetchByGenre{genre, offset, limit})).unwrap()
+    }
+    /// Return the albums from a range of years, newest first if `from` is after `to`
+    pub fn fetch_by_year(&self, from: u16, to: u16)                            -> Vec<Item>
+    {
+        serde_json::from_str::<Vec<Item>>(&self.send_command(Commands::FetchByYear{from, to})).unwrap()
+    }
+    /// Lazily fetch the results of a query, a chunk at a time
+    pub fn stream_library(&self, library: Library, query: LibraryQuery, chunk: usize) -> LibraryStream
+    {
+        LibraryStream { conn: self.open(Commands::StreamLibrary{library, query, chunk}), items: VecDeque::new(), done: false }
     }
     /// Tell the Subsonic server to rescan
     pub fn scan(&self)                                                  -> bool
@@ -351,10 +846,10 @@ impl Client {
     {
         serde_json::from_str::<bool>(&self.send_command(Commands::Skip)).unwrap()
     }
-    /// Add a song to the queue
-    pub fn queue_add(&self, id: Item, position: u8)                     -> bool
+    /// Add a song or radio station to the queue, starting from its bookmark when auto resume is on
+    pub fn queue_add(&self, id: Item, position: u8, quality: Option<Transcoding>) -> bool
     {
-        serde_json::from_str::<bool>(&self.send_command(Commands::QueueAdd{id, position})).unwrap()
+        serde_json::from_str::<bool>(&self.send_command(Commands::QueueAdd{id, position, quality})).unwrap()
     }
     /// Remove a song from the queue
     pub fn queue_remove(&self, index: u8)                                -> bool
@@ -372,14 +867,14 @@ impl Client {
         serde_json::from_str::<bool>(&self.send_command(Commands::VolumeSet(amount))).unwrap()
     }
     /// Search for a query
-    pub fn search(&self, query: String)                                 -> Vec<Item>
+    pub fn search(&self, query: SearchQuery)                            -> SearchResults
     {
-        serde_json::from_str::<Vec<Item>>(&self.send_command(Commands::Search(query))).unwrap()
+        serde_json::from_str::<SearchResults>(&self.send_command(Commands::Search(query))).unwrap()
     }
-    /// Download a song for offline playback
-    pub fn download(&self, id: Item)                                    -> bool
+    /// Download a song or podcast episode for offline playback, at the stream quality if none is given
+    pub fn download(&self, id: Item, quality: Option<Transcoding>)      -> bool
     {
-        serde_json::from_str::<bool>(&self.send_command(Commands::Download(id))).unwrap()
+        serde_json::from_str::<bool>(&self.send_command(Commands::Download{id, quality})).unwrap()
     }
     /// Delete a song from offline playback
     pub fn delete(&self, id: Item)                                      -> bool
@@ -391,36 +886,96 @@ impl Client {
     {
         serde_json::from_str::<bool >(&self.send_command(Commands::Star(id))).unwrap()
     }
-    /// Download all the songs from a playlist
-    pub fn playlist_download(&self, id: Item)                           -> bool
+    /// Remove a favorite on the Subsonic server
+    pub fn unstar(&self, id: Item)                                      -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::Unstar(id))).unwrap()
+    }
+    /// Rate a song from 1 to 5, 0 removes the rating
+    pub fn set_rating(&self, id: Item, rating: u8)                      -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::SetRating{id, rating})).unwrap()
+    }
+    /// Return all favorites
+    pub fn fetch_starred(&self)                                         -> Starred
+    {
+        serde_json::from_str::<Starred>(&self.send_command(Commands::FetchStarred)).unwrap()
+    }
+    /// Merge server changes into a local playlist and download its songs
+    pub fn playlist_download(&self, id: Item)                           -> SyncResult
     {
-        serde_json::from_str::<bool>(&self.send_command(Commands::PlaylistDownload(id))).unwrap()
+        serde_json::from_str::<SyncResult>(&self.send_command(Commands::PlaylistDownload(id))).unwrap()
     }
-    /// Upload changes on a local playlist
-    pub fn playlist_upload(&self, id: Item)                             -> bool
+    /// Merge and upload changes on a local playlist
+    pub fn playlist_upload(&self, id: Item)                             -> SyncResult
     {
-        serde_json::from_str::<bool>(&self.send_command(Commands::PlaylistUpload(id))).unwrap()
+        serde_json::from_str::<SyncResult>(&self.send_command(Commands::PlaylistUpload(id))).unwrap()
     }
     /// Create a new local playlist
     pub fn playlist_new(&self, name: String)                            -> bool
     {
         serde_json::from_str::<bool>(&self.send_command(Commands::PlaylistNew{name})).unwrap()
     }
-    /// Add to a local playlist
-    pub fn playlist_add_to(&self, playlist: Item, id: Item)             -> bool
+    /// Create a smart playlist from a rule, see `smart::Rule::parse` to check it first
+    pub fn smart_playlist_new(&self, name: String, rule: String)        -> bool
     {
-        serde_json::from_str::<bool>(&self.send_command(Commands::PlaylistAddTo{playlist, id})).unwrap()
+        serde_json::from_str::<bool>(&self.send_command(Commands::SmartPlaylistNew{name, rule})).unwrap()
+    }
+    /// Re-evaluate the rule of a smart playlist
+    pub fn playlist_refresh(&self, id: Item)                            -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::PlaylistRefresh(id))).unwrap()
+    }
+    /// Add to a local playlist, at the end if there is no position
+    pub fn playlist_add_to(&self, playlist: Item, id: Item, position: Option<usize>) -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::PlaylistAddTo{playlist, id, position})).unwrap()
     }
     /// Remove from a local playlist
     pub fn playlist_remove_from(&self, playlist: Item, id: Item)        -> bool 
     {
         serde_json::from_str::<bool>(&self.send_command(Commands::PlaylistRemoveFrom{playlist, id})).unwrap()
     }
+    /// Remove the song at an index from a local playlist
+    pub fn playlist_remove_at(&self, playlist: Item, index: usize)      -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::PlaylistRemoveAt{playlist, index})).unwrap()
+    }
+    /// Move a song within a local playlist
+    pub fn playlist_move(&self, playlist: Item, from: usize, to: usize) -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::PlaylistMove{playlist, from, to})).unwrap()
+    }
+    /// Rename a local playlist
+    pub fn playlist_rename(&self, playlist: Item, name: String)         -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::PlaylistRename{playlist, name})).unwrap()
+    }
+    /// Set the comment on a local playlist
+    pub fn playlist_set_comment(&self, playlist: Item, comment: String) -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::PlaylistSetComment{playlist, comment})).unwrap()
+    }
+    /// Set whether a local playlist is public
+    pub fn playlist_set_public(&self, playlist: Item, public: bool)     -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::PlaylistSetPublic{playlist, public})).unwrap()
+    }
     /// Delete a local playlist
     pub fn playlist_delete(&self, id: Item)                             -> bool
     {
         serde_json::from_str::<bool>(&self.send_command(Commands::PlaylistDelete(id))).unwrap()
     }
+    /// Create a local playlist from a playlist file
+    pub fn playlist_import(&self, format: PlaylistFormat, data: String) -> Option<ImportResult>
+    {
+        serde_json::from_str::<Option<ImportResult>>(&self.send_command(Commands::PlaylistImport{format, data})).unwrap()
+    }
+    /// Write a local playlist out as a playlist file
+    pub fn playlist_export(&self, playlist: Item, format: PlaylistFormat) -> Option<String>
+    {
+        serde_json::from_str::<Option<String>>(&self.send_command(Commands::PlaylistExport{playlist, format})).unwrap()
+    }
     /// Get the info of a song
     pub fn song_info(&self, id: Item)                                   -> Option<SongInfo>
     {
@@ -431,6 +986,185 @@ impl Client {
     {
         serde_json::from_str::<AlbumInfo>(&self.send_command(Commands::AlbumInfo(id))).unwrap()
     }
+    /// Get the cover art of an item, scaled to a size in pixels, as bytes if inline
+    pub fn cover_art(&self, id: Item, size: Option<u32>, inline: bool)  -> Option<CoverArt>
+    {
+        serde_json::from_str::<Option<CoverArt>>(&self.send_command(Commands::CoverArt{id, size, inline})).unwrap()
+    }
+    /// Get the lyrics of a song, synced if there are timestamps
+    pub fn lyrics(&self, id: Item)                                      -> Option<Lyrics>
+    {
+        serde_json::from_str::<Option<Lyrics>>(&self.send_command(Commands::Lyrics(id))).unwrap()
+    }
+    /// Return all internet radio stations
+    pub fn fetch_radio_stations(&self)                                  -> Vec<RadioStation>
+    {
+        serde_json::from_str::<Vec<RadioStation>>(&self.send_command(Commands::FetchRadioStations)).unwrap()
+    }
+    /// Add an internet radio station on the Subsonic server
+    pub fn radio_create(&self, name: String, stream_url: String, homepage_url: Option<String>) -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::RadioCreate{name, stream_url, homepage_url})).unwrap()
+    }
+    /// Remove an internet radio station from the Subsonic server
+    pub fn radio_delete(&self, id: Item)                                -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::RadioDelete(id))).unwrap()
+    }
+    /// Return all podcast channels
+    pub fn fetch_podcasts(&self)                                        -> Vec<PodcastChannel>
+    {
+        serde_json::from_str::<Vec<PodcastChannel>>(&self.send_command(Commands::FetchPodcasts)).unwrap()
+    }
+    /// Return the episodes of a podcast channel, with where to resume them
+    pub fn fetch_episodes(&self, channel: Item)                         -> Vec<PodcastEpisode>
+    {
+        serde_json::from_str::<Vec<PodcastEpisode>>(&self.send_command(Commands::FetchEpisodes(channel))).unwrap()
+    }
+    /// Subscribe to a podcast by feed url
+    pub fn podcast_subscribe(&self, url: String)                        -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::PodcastSubscribe{url})).unwrap()
+    }
+    /// Unsubscribe from a podcast channel
+    pub fn podcast_unsubscribe(&self, channel: Item)                    -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::PodcastUnsubscribe(channel))).unwrap()
+    }
+    /// Mark a podcast episode played or unplayed
+    pub fn podcast_mark_played(&self, episode: Item, played: bool)      -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::PodcastMarkPlayed{episode, played})).unwrap()
+    }
+    /// Save where to resume an item, replacing its old bookmark
+    pub fn bookmark_set(&self, item: Item, position: Duration, comment: Option<String>) -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::BookmarkSet{item, position, comment})).unwrap()
+    }
+    /// Remove the bookmark of an item
+    pub fn bookmark_delete(&self, item: Item)                           -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::BookmarkDelete(item))).unwrap()
+    }
+    /// Return all bookmarks
+    pub fn fetch_bookmarks(&self)                                       -> Vec<Bookmark>
+    {
+        serde_json::from_str::<Vec<Bookmark>>(&self.send_command(Commands::FetchBookmarks)).unwrap()
+    }
+    /// Set whether queueing a bookmarked item resumes it
+    pub fn bookmark_auto_resume(&self, enabled: bool)                   -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::BookmarkAutoResume(enabled))).unwrap()
+    }
+    /// Save the queue to the Subsonic server, to carry on from another machine
+    pub fn play_queue_upload(&self)                                     -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::PlayQueueUpload)).unwrap()
+    }
+    /// Take the queue saved on the Subsonic server
+    pub fn play_queue_download(&self)                                   -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::PlayQueueDownload)).unwrap()
+    }
+    /// Return the server profiles
+    pub fn profile_list(&self)                                          -> Vec<Profile>
+    {
+        serde_json::from_str::<Vec<Profile>>(&self.send_command(Commands::ProfileList)).unwrap()
+    }
+    /// Add a server profile
+    pub fn profile_add(&self, profile: Profile)                         -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::ProfileAdd(profile))).unwrap()
+    }
+    /// Remove a server profile other than the active one
+    pub fn profile_remove(&self, name: String)                          -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::ProfileRemove{name})).unwrap()
+    }
+    /// Switch to another server profile, along with its cache and playlists
+    pub fn profile_switch(&self, name: String)                          -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::ProfileSwitch{name})).unwrap()
+    }
+    /// Check a login against the server and keep the password encrypted, it is never sent back
+    pub fn login(&self, url: String, user: String, password: Secret)    -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::Login{url, user, password})).unwrap()
+    }
+    /// Read the config file again, also done on SIGHUP
+    pub fn reload_config(&self)                                         -> Result<(), String>
+    {
+        serde_json::from_str::<Result<(), String>>(&self.send_command(Commands::ReloadConfig)).unwrap()
+    }
+    /// Set the stream quality of the active profile, unset fields fall back to the config
+    pub fn set_stream_quality(&self, quality: Transcoding)              -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::SetStreamQuality(quality))).unwrap()
+    }
+    /// Set how long tracks overlap, zero plays them back to back with no gap
+    pub fn set_crossfade(&self, crossfade: Duration)                    -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::SetCrossfade(crossfade))).unwrap()
+    }
+    /// Set the ReplayGain mode, preamp and clipping prevention, applied along with the volume
+    pub fn set_replay_gain(&self, replay_gain: ReplayGain)              -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::SetReplayGain(replay_gain))).unwrap()
+    }
+    /// Set the equalizer bands, no bands turns it off
+    pub fn eq_set(&self, bands: Vec<Band>)                              -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::EqSet(bands))).unwrap()
+    }
+    /// Save the current equalizer bands as a preset
+    pub fn eq_preset_save(&self, name: String)                          -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::EqPresetSave{name})).unwrap()
+    }
+    /// Set the equalizer to a saved or built in preset: flat, bass boost or voice
+    pub fn eq_preset_load(&self, name: String)                          -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::EqPresetLoad{name})).unwrap()
+    }
+    /// Set the playback speed from 0.5 to 3, keeping the pitch
+    pub fn set_playback_rate(&self, rate: f32)                          -> bool
+    {
+        serde_json::from_str::<bool>(&self.send_command(Commands::SetPlaybackRate(rate))).unwrap()
+    }
+}
+
+/// Iterator over a library streamed from the daemon
+pub struct LibraryStream {
+    conn: BufReader<Stream>,
+    items: VecDeque<Item>,
+    done: bool,
+}
+
+impl Iterator for LibraryStream {
+    type Item = Item;
+
+    fn next(&mut self) -> Option<Item> {
+        while self.items.is_empty() && !self.done {
+            let mut buffer = String::new();
+            // A closed connection ends the stream as well
+            if self.conn.read_line(&mut buffer).unwrap_or(0) == 0 {
+                self.done = true;
+                break;
+            }
+            let chunk = serde_json::from_str::<Vec<Item>>(buffer.trim_end()).unwrap();
+            self.done = chunk.is_empty();
+            self.items.extend(chunk);
+        }
+        self.items.pop_front()
+    }
+}
+
+/// The libraries that can be paged through
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Library {
+    Artists,
+    Albums,
+    Songs,
 }
 
 #[derive(Deserialize,Serialize, Clone)]
@@ -439,26 +1173,97 @@ pub struct Status {
     pub current_song: Option<Item>,
     pub queue: VecDeque<Item>,
     pub volume: f32,
+    /// How far into the current song playback is
+    #[serde(default)]
+    pub position: Duration,
+    /// The name of the server profile in use
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// The stream quality in use, after falling back to the profile and config
+    #[serde(default)]
+    pub quality: Transcoding,
+    /// How long tracks overlap, zero is gapless
+    #[serde(default)]
+    pub crossfade: Duration,
+    #[serde(default)]
+    pub replay_gain: ReplayGain,
+    /// The equalizer bands in use, empty when it is off
+    #[serde(default)]
+    pub eq: Vec<Band>,
+    /// Playback speed, 2 plays a song in half its length
+    #[serde(default = "default_rate")]
+    pub rate: f32,
+    /// Live metadata while a radio station plays, since it has no fixed length
+    #[serde(default)]
+    pub stream: Option<StreamMetadata>,
+}
+
+fn default_rate() -> f32 { 1.0 }
+
+impl Status {
+    /// Listening time left in a song of `length`, shorter when playing fast
+    pub fn remaining(&self, length: Duration) -> Duration
+    {
+        length.saturating_sub(self.position).div_f32(self.rate)
+    }
+
+    /// Where in the song to seek to, to move by `offset` of listening time
+    pub fn seek_target(&self, offset: Duration, forward: bool) -> Duration
+    {
+        let offset = offset.mul_f32(self.rate);
+        match forward {
+            true => self.position + offset,
+            false => self.position.saturating_sub(offset),
+        }
+    }
 }
 
 #[derive(Deserialize,Serialize, Debug, PartialEq, Eq, Clone)]
 pub struct Item {
     pub name: String,
     pub id: String,
+    /// The cover art id to look up with `CoverArt`, empty if there is none
     pub image_path: String,
 }
 
-#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
 pub struct SongInfo {
     pub length: Duration,
     pub album: Item,
     pub artist: String,
+    #[serde(default)]
+    pub genre: Option<String>,
+    #[serde(default)]
+    pub year: Option<u16>,
+    /// User rating from 1 to 5
+    #[serde(default)]
+    pub rating: Option<u8>,
+    #[serde(default)]
+    pub starred: bool,
 }
 
 #[derive(Deserialize,Serialize)]
 pub struct AlbumInfo {
     pub songs: Vec<Item>,
     pub artist: String,
+    #[serde(default)]
+    pub genre: Option<String>,
+    #[serde(default)]
+    pub year: Option<u16>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct Genre {
+    pub name: String,
+    pub song_count: u32,
+    pub album_count: u32,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Default)]
+pub struct Starred {
+    pub songs: Vec<Item>,
+    pub albums: Vec<Item>,
+    pub artists: Vec<Item>,
 }
 
 
@@ -479,6 +1284,10 @@ mod tests {
                     name: String::from("Some Album")
                 },
                 artist: String::from("Some Artist"),
+                genre: None,
+                year: None,
+                rating: None,
+                starred: false,
             }
         }
     }
@@ -540,8 +1349,8 @@ mod tests {
             todo!()
         }
 
-        fn queue_add(&mut self, id: Item, position: u8)                -> bool {
-            let _ = (id, position);
+        fn queue_add(&mut self, id: Item, position: u8, quality: Option<Transcoding>) -> bool {
+            let _ = (id, position, quality);
             todo!()
         }
 
@@ -560,19 +1369,19 @@ mod tests {
             todo!()
         }
 
-        fn search(&self, query: String)                               -> Vec<Item> {
-            if  query == buffer_test!()
+        fn search(&self, query: SearchQuery)                          -> SearchResults {
+            if  query.text == buffer_test!()
             {
-                vec_item!()
+                SearchResults { songs: vec_item!(), ..Default::default() }
             }
             else
             {
-                vec!()
+                SearchResults::default()
             }
         }
 
-        fn download(&self, id: Item)                                    -> bool {
-            let _ = id;
+        fn download(&self, id: Item, quality: Option<Transcoding>)      -> bool {
+            let _ = (id, quality);
             todo!()
         }
 
@@ -586,12 +1395,26 @@ mod tests {
             todo!()
         }
 
-        fn playlist_download(&self, id: Item)                           -> bool {
+        fn unstar(&self, id: Item)                                      -> bool {
             let _ = id;
             todo!()
         }
 
-        fn playlist_upload(&self, id: Item)                             -> bool {
+        fn set_rating(&self, id: Item, rating: u8)                      -> bool {
+            let _ = (id, rating);
+            todo!()
+        }
+
+        fn fetch_starred(&mut self)                                     -> Starred {
+            todo!()
+        }
+
+        fn playlist_download(&self, id: Item)                           -> SyncResult {
+            let _ = id;
+            todo!()
+        }
+
+        fn playlist_upload(&self, id: Item)                             -> SyncResult {
             let _ = id;
             todo!()
         }
@@ -601,8 +1424,18 @@ mod tests {
             todo!()
         }
 
-        fn playlist_add_to(&self, playlist: Item, id: Item)             -> bool {
-            let _ = (id, playlist);
+        fn smart_playlist_new(&self, name: String, rule: String)        -> bool {
+            let _ = (name, rule);
+            todo!()
+        }
+
+        fn playlist_refresh(&self, id: Item)                            -> bool {
+            let _ = id;
+            todo!()
+        }
+
+        fn playlist_add_to(&self, playlist: Item, id: Item, position: Option<usize>) -> bool {
+            let _ = (id, playlist, position);
             todo!()
         }
 
@@ -611,11 +1444,46 @@ mod tests {
             todo!()
         }
 
+        fn playlist_remove_at(&self, playlist: Item, index: usize)      -> bool {
+            let _ = (playlist, index);
+            todo!()
+        }
+
+        fn playlist_move(&self, playlist: Item, from: usize, to: usize) -> bool {
+            let _ = (playlist, from, to);
+            todo!()
+        }
+
+        fn playlist_rename(&self, playlist: Item, name: String)         -> bool {
+            let _ = (playlist, name);
+            todo!()
+        }
+
+        fn playlist_set_comment(&self, playlist: Item, comment: String) -> bool {
+            let _ = (playlist, comment);
+            todo!()
+        }
+
+        fn playlist_set_public(&self, playlist: Item, public: bool)     -> bool {
+            let _ = (playlist, public);
+            todo!()
+        }
+
         fn playlist_delete(&self, id: Item)                             -> bool {
             let _ = id;
             todo!()
         }
 
+        fn playlist_import(&self, format: PlaylistFormat, data: String) -> Option<ImportResult> {
+            let _ = (format, data);
+            todo!()
+        }
+
+        fn playlist_export(&self, playlist: Item, format: PlaylistFormat) -> Option<String> {
+            let _ = (playlist, format);
+            todo!()
+        }
+
         fn song_info(&self, id: Item)                                   -> Option<SongInfo> {
             let _ = id;
             Some(song_info!())
@@ -626,11 +1494,157 @@ mod tests {
             todo!()
         }
 
-        fn fetch_artists(&mut self)                                         -> Vec<Item> {
+        fn cover_art(&self, id: Item, size: Option<u32>, inline: bool)  -> Option<CoverArt> {
+            let _ = (id, size, inline);
             todo!()
         }
 
-        fn fetch_albums(&mut self)                                          -> Vec<Item> {
+        fn lyrics(&self, id: Item)                                      -> Option<Lyrics> {
+            let _ = id;
+            todo!()
+        }
+
+        fn fetch_radio_stations(&mut self)                              -> Vec<RadioStation> {
+            todo!()
+        }
+
+        fn radio_create(&self, name: String, stream_url: String, homepage_url: Option<String>) -> bool {
+            let _ = (name, stream_url, homepage_url);
+            todo!()
+        }
+
+        fn radio_delete(&self, id: Item)                                -> bool {
+            let _ = id;
+            todo!()
+        }
+
+        fn fetch_podcasts(&mut self)                                    -> Vec<PodcastChannel> {
+            todo!()
+        }
+
+        fn fetch_episodes(&mut self, channel: Item)                     -> Vec<PodcastEpisode> {
+            let _ = channel;
+            todo!()
+        }
+
+        fn podcast_subscribe(&self, url: String)                        -> bool {
+            let _ = url;
+            todo!()
+        }
+
+        fn podcast_unsubscribe(&self, channel: Item)                    -> bool {
+            let _ = channel;
+            todo!()
+        }
+
+        fn podcast_mark_played(&mut self, episode: Item, played: bool)  -> bool {
+            let _ = (episode, played);
+            todo!()
+        }
+
+        fn bookmark_set(&mut self, item: Item, position: Duration, comment: Option<String>) -> bool {
+            let _ = (item, position, comment);
+            todo!()
+        }
+
+        fn bookmark_delete(&mut self, item: Item)                       -> bool {
+            let _ = item;
+            todo!()
+        }
+
+        fn fetch_bookmarks(&mut self)                                   -> Vec<Bookmark> {
+            todo!()
+        }
+
+        fn bookmark_auto_resume(&mut self, enabled: bool)               -> bool {
+            let _ = enabled;
+            todo!()
+        }
+
+        fn play_queue_upload(&self)                                     -> bool {
+            todo!()
+        }
+
+        fn play_queue_download(&mut self)                               -> bool {
+            todo!()
+        }
+
+        fn restore(&mut self, state: PlaybackState)                     -> bool {
+            let _ = state;
+            todo!()
+        }
+
+        fn profile_list(&self)                                          -> Vec<Profile> {
+            todo!()
+        }
+
+        fn profile_add(&mut self, profile: Profile)                     -> bool {
+            let _ = profile;
+            todo!()
+        }
+
+        fn profile_remove(&mut self, name: String)                      -> bool {
+            let _ = name;
+            todo!()
+        }
+
+        fn profile_switch(&mut self, name: String)                      -> bool {
+            let _ = name;
+            todo!()
+        }
+
+        fn login(&mut self, url: String, user: String, password: Secret) -> bool {
+            let _ = (url, user, password);
+            todo!()
+        }
+
+        fn configure(&mut self, config: Config)                         -> bool {
+            let _ = config;
+            todo!()
+        }
+
+        fn set_stream_quality(&mut self, quality: Transcoding)          -> bool {
+            let _ = quality;
+            todo!()
+        }
+
+        fn set_crossfade(&mut self, crossfade: Duration)                -> bool {
+            let _ = crossfade;
+            todo!()
+        }
+
+        fn set_replay_gain(&mut self, replay_gain: ReplayGain)          -> bool {
+            let _ = replay_gain;
+            todo!()
+        }
+
+        fn eq_set(&mut self, bands: Vec<Band>)                          -> bool {
+            let _ = bands;
+            todo!()
+        }
+
+        fn eq_preset_save(&mut self, name: String)                      -> bool {
+            let _ = name;
+            todo!()
+        }
+
+        fn eq_preset_load(&mut self, name: String)                      -> bool {
+            let _ = name;
+            todo!()
+        }
+
+        fn set_playback_rate(&mut self, rate: f32)                      -> bool {
+            let _ = rate;
+            todo!()
+        }
+
+        fn fetch_artists(&mut self, query: LibraryQuery)                    -> Vec<Item> {
+            let _ = query;
+            todo!()
+        }
+
+        fn fetch_albums(&mut self, query: LibraryQuery)                     -> Vec<Item> {
+            let _ = query;
             todo!()
         }
 
@@ -638,9 +1652,20 @@ mod tests {
             todo!()
         }
 
-        fn fetch_songs(&mut self)                                           -> Vec<Item> {
+        fn fetch_genres(&mut self)                                          -> Vec<Genre> {
             todo!()
         }
+
+        fn fetch_songs(&mut self, query: LibraryQuery)                      -> Vec<Item> {
+            let songs: Vec<Item> = vec_item!();
+            songs.into_iter().skip(query.offset).take(query.limit.unwrap_or(usize::MAX)).collect()
+        }
+
+        // Like an episode the server hasn't fetched yet
+        fn media_id(&self, id: &str) -> Option<String> {
+            let _ = id;
+            None
+        }
     }
 
     #[test]
@@ -651,11 +1676,25 @@ mod tests {
             test_server.start();
         });
 
+        // Nothing is listening there, which is an error rather than a panic
+        assert!(matches!(Client::connect("slib-missing.socket"), Err(SlibError::Io(_))));
+
         thread::sleep(Duration::from_secs(1));
         let client = Client::new().unwrap();
 
         assert_eq!(song_info!(), client.song_info(item!()).unwrap());
-        assert_eq!(vec_item!(), client.search(buffer_test!()));
+        // Never reaches the daemon, which would panic
+        assert!(!client.set_rating(item!(), 9));
+        assert!(!client.download(item!(), None));
+        assert!(!client.queue_add(item!(), 0, None));
+        assert_eq!(vec_item!(), client.search(SearchQuery { text: buffer_test!(), ..Default::default() }).songs);
+        let page = LibraryQuery{offset: 2, limit: Some(3), ..Default::default()};
+        assert_eq!(vec_item!()[2..5], client.fetch_songs(page.clone()));
+        assert_eq!(vec_item!()[2..5], client.stream_library(Library::Songs, page, 2).collect::<Vec<_>>());
+        assert_eq!(vec_item!(), client.stream_library(Library::Songs, LibraryQuery::default(), 3).collect::<Vec<_>>());
+        assert_eq!(vec_item!(), client.stream_library(Library::Songs, LibraryQuery::default(), 7).collect::<Vec<_>>());
+        let huge = LibraryQuery{offset: 2, limit: Some(usize::MAX), ..Default::default()};
+        assert_eq!(vec_item!()[2..], client.stream_library(Library::Songs, huge, 3).collect::<Vec<_>>());
 
         client.shutdown();
     }
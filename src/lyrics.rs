@@ -0,0 +1,394 @@
+use std::{fs, io, path::{Path, PathBuf}, time::Duration};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{subsonic, SlibError};
+
+/// A line of lyrics, with when it starts if the lyrics are synced
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct Line {
+    pub start: Option<Duration>,
+    pub text: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct Lyrics {
+    pub synced: bool,
+    pub lines: Vec<Line>,
+}
+
+impl Lyrics {
+    /// Lyrics without timing
+    pub fn plain(text: &str) -> Lyrics
+    {
+        Lyrics {
+            synced: false,
+            lines: text.lines().map(|l| Line { start: None, text: l.trim_end().to_string() }).collect(),
+        }
+    }
+
+    /// Parse LRC, falling back to plain lyrics if there are no timestamps
+    pub fn parse_lrc(text: &str) -> Lyrics
+    {
+        let mut offset: i64 = 0;
+        let mut lines = Vec::new();
+
+        for raw in text.lines() {
+            let mut rest = raw.trim();
+            let mut stamps = Vec::new();
+
+            // A line can have several timestamps when it repeats, like [00:12.00][01:30.50]
+            while let Some(tag) = rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+                let (tag, after) = tag;
+                if let Some(time) = parse_timestamp(tag) {
+                    stamps.push(time);
+                }
+                else if let Some(value) = tag.strip_prefix("offset:") {
+                    offset = value.trim().parse().unwrap_or(0);
+                }
+                rest = after;
+            }
+
+            for stamp in stamps {
+                // A positive offset makes lyrics come sooner
+                let shift = Duration::from_millis(offset.unsigned_abs());
+                let start = match offset >= 0 {
+                    true => stamp.saturating_sub(shift),
+                    false => stamp.saturating_add(shift),
+                };
+                lines.push(Line { start: Some(start), text: rest.trim().to_string() });
+            }
+        }
+
+        if lines.is_empty() {
+            return Lyrics::plain(text);
+        }
+        lines.sort_by_key(|l| l.start);
+        Lyrics { synced: true, lines }
+    }
+
+    /// Write the lyrics out as LRC
+    pub fn to_lrc(&self) -> String
+    {
+        let mut out = String::new();
+        for line in &self.lines {
+            if let Some(start) = line.start {
+                let millis = start.as_millis();
+                out += &format!("[{:02}:{:02}.{:02}]", millis / 60_000, millis / 1000 % 60, millis % 1000 / 10);
+            }
+            out += &line.text;
+            out += "\n";
+        }
+        out
+    }
+
+    /// Read an OpenSubsonic `structuredLyrics` entry
+    pub fn from_structured(value: &Value) -> Option<Lyrics>
+    {
+        let synced = value["synced"].as_bool().unwrap_or(false);
+        let offset = value["offset"].as_i64().unwrap_or(0);
+        let lines = value["line"].as_array()?.iter().map(|line| Line {
+            start: line["start"].as_i64()
+                .filter(|_| synced)
+                .map(|start| Duration::from_millis(start.saturating_sub(offset).max(0) as u64)),
+            text: line["value"].as_str().unwrap_or_default().to_string(),
+        }).collect();
+        Some(Lyrics { synced, lines })
+    }
+
+    /// The index of the line being sung at a position in the song
+    pub fn line_at(&self, position: Duration) -> Option<usize>
+    {
+        if !self.synced {
+            return None;
+        }
+        self.lines.iter().rposition(|l| l.start.is_some_and(|s| s <= position))
+    }
+}
+
+/// Parse mm:ss.xx, returns None for metadata tags like [ar:Artist] and for times that make no sense
+fn parse_timestamp(tag: &str) -> Option<Duration>
+{
+    let (minutes, seconds) = tag.split_once(':')?;
+    let minutes: u64 = minutes.trim().parse().ok()?;
+    let seconds: f64 = seconds.trim().parse().ok()?;
+    Duration::from_secs(minutes.checked_mul(60)?).checked_add(Duration::try_from_secs_f64(seconds).ok()?)
+}
+
+/// Ask the server with `getLyricsBySongId`, preferring synced lyrics
+pub fn fetch(server: &subsonic::Server, song_id: &str) -> Result<Option<Lyrics>, SlibError>
+{
+    let response = server.call("getLyricsBySongId", &[("id", song_id)])?;
+    let all = response["lyricsList"]["structuredLyrics"].as_array().cloned().unwrap_or_default();
+    let best = all.iter().find(|l| l["synced"] == true).or(all.first());
+    Ok(best.and_then(Lyrics::from_structured).filter(|l| !l.lines.is_empty()))
+}
+
+/// Read a `.lrc` file next to a downloaded song
+pub fn sidecar(song: &Path) -> Option<Lyrics>
+{
+    let text = fs::read_to_string(song.with_extension("lrc")).ok()?;
+    Some(Lyrics::parse_lrc(&text))
+}
+
+/// Read lyrics from the tags of a downloaded song, ID3v2 `USLT` or a FLAC `LYRICS` comment
+pub fn embedded(song: &Path) -> Option<Lyrics>
+{
+    let data = fs::read(song).ok()?;
+    let text = if data.starts_with(b"ID3") {
+        id3_lyrics(&data)?
+    }
+    else if data.starts_with(b"fLaC") {
+        flac_lyrics(&data)?
+    }
+    else {
+        return None;
+    };
+    Some(Lyrics::parse_lrc(&text))
+}
+
+fn syncsafe(bytes: &[u8]) -> usize
+{
+    bytes.iter().fold(0, |n, &b| (n << 7) | (b & 0x7f) as usize)
+}
+
+/// The frames of an ID3v2 tag, by id
+pub(crate) fn id3_frames(data: &[u8]) -> Vec<(&[u8], &[u8])>
+{
+    let mut frames = Vec::new();
+    let (Some(&version), Some(size)) = (data.get(3), data.get(6..10)) else { return frames };
+    let end = 10 + syncsafe(size);
+    let mut pos = 10;
+
+    while pos + 10 <= end.min(data.len()) {
+        let id = &data[pos..pos + 4];
+        if id[0] == 0 {
+            // Padding
+            break;
+        }
+        let size_bytes = &data[pos + 4..pos + 8];
+        let size = if version >= 4 {
+            syncsafe(size_bytes)
+        }
+        else {
+            size_bytes.iter().fold(0, |n, &b| (n << 8) | b as usize)
+        };
+        let Some(body) = data.get(pos + 10..pos + 10 + size) else { break };
+        frames.push((id, body));
+        pos += 10 + size;
+    }
+    frames
+}
+
+/// Split the text after an encoding byte at the first null, returning what comes after it
+pub(crate) fn skip_terminated(encoding: u8, text: &[u8]) -> Option<&[u8]>
+{
+    let wide = encoding == 1 || encoding == 2;
+    let skip = if wide {
+        text.chunks(2).position(|c| c == [0, 0]).map(|i| i * 2 + 2)
+    }
+    else {
+        text.iter().position(|&b| b == 0).map(|i| i + 1)
+    }?;
+    text.get(skip..)
+}
+
+fn id3_lyrics(data: &[u8]) -> Option<String>
+{
+    let (_, body) = id3_frames(data).into_iter().find(|(id, body)| *id == b"USLT" && body.len() > 4)?;
+    // Encoding, then language, then a description we skip
+    let encoding = body[0];
+    decode(encoding, skip_terminated(encoding, &body[4..])?)
+}
+
+/// Decode ID3v2 text in one of its encodings
+pub(crate) fn decode(encoding: u8, bytes: &[u8]) -> Option<String>
+{
+    match encoding {
+        0 => Some(bytes.iter().map(|&b| b as char).collect()),
+        1 | 2 => {
+            let mut units: Vec<u16> = bytes.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+            // UTF-16 with a byte order mark, which flips the order when little endian
+            if encoding == 1 {
+                match units.first() {
+                    Some(0xfffe) => { units = units.iter().map(|u| u.swap_bytes()).collect(); units.remove(0); },
+                    Some(0xfeff) => { units.remove(0); },
+                    _ => {},
+                }
+            }
+            String::from_utf16(&units).ok()
+        },
+        3 => String::from_utf8(bytes.to_vec()).ok(),
+        _ => None,
+    }
+    .map(|s| s.trim_end_matches('\0').to_string())
+}
+
+/// The Vorbis comments of a FLAC file, as key and value
+pub(crate) fn flac_comments(data: &[u8]) -> Vec<(String, String)>
+{
+    let mut comments = Vec::new();
+    let mut pos = 4;
+    while let Some(header) = data.get(pos..pos + 4) {
+        let last = header[0] & 0x80 != 0;
+        let kind = header[0] & 0x7f;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let Some(block) = data.get(pos + 4..pos + 4 + len) else { break };
+
+        // Vorbis comments, all little endian unlike the rest of FLAC
+        if kind == 4 {
+            let read_u32 = |at: usize| block.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize);
+            let Some(mut at) = read_u32(0).map(|vendor| 4 + vendor) else { break };
+            let count = read_u32(at).unwrap_or(0);
+            at += 4;
+            for _ in 0..count {
+                let Some(comment) = read_u32(at).and_then(|len| block.get(at + 4..at + 4 + len)) else { break };
+                at += 4 + comment.len();
+                if let Some((key, value)) = String::from_utf8_lossy(comment).split_once('=') {
+                    comments.push((key.to_string(), value.to_string()));
+                }
+            }
+        }
+
+        if last {
+            break;
+        }
+        pos += 4 + len;
+    }
+    comments
+}
+
+fn flac_lyrics(data: &[u8]) -> Option<String>
+{
+    flac_comments(data).into_iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("LYRICS") || key.eq_ignore_ascii_case("UNSYNCEDLYRICS"))
+        .map(|(_, value)| value)
+}
+
+/// Lyrics saved on disk so they work offline
+pub struct LyricsCache {
+    dir: PathBuf,
+}
+
+impl LyricsCache {
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<LyricsCache>
+    {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(LyricsCache{dir})
+    }
+
+    fn path(&self, song_id: &str) -> PathBuf
+    {
+        // Hashed, so ids that only differ in characters a file name can't have stay apart
+        self.dir.join(format!("{:x}.json", md5::compute(song_id)))
+    }
+
+    pub fn get(&self, song_id: &str) -> Option<Lyrics>
+    {
+        let data = fs::read_to_string(self.path(song_id)).ok()?;
+        serde_json::from_str(&data).ok()
+    }
+
+    pub fn insert(&self, song_id: &str, lyrics: &Lyrics) -> io::Result<()>
+    {
+        // Write to the side and rename so a crash never leaves half an entry
+        let path = self.path(song_id);
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string(lyrics)?)?;
+        fs::rename(tmp, path)
+    }
+
+    /// Find lyrics in the cache, then next to or inside the downloaded file, then on the server
+    pub fn find(&self, song_id: &str, file: Option<&Path>, server: Option<&subsonic::Server>) -> Option<Lyrics>
+    {
+        if let Some(lyrics) = self.get(song_id) {
+            return Some(lyrics);
+        }
+        let lyrics = file.and_then(|f| sidecar(f).or_else(|| embedded(f)))
+            .or_else(|| server.and_then(|s| fetch(s, song_id).ok().flatten()))?;
+        let _ = self.insert(song_id, &lyrics);
+        Some(lyrics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lrc() {
+        let lyrics = Lyrics::parse_lrc("\
+[ar:Someone]
+[offset:500]
+[00:12.00][01:30.50]Chorus
+[00:05.25]Verse
+");
+        assert!(lyrics.synced);
+        assert_eq!(vec!["Verse", "Chorus", "Chorus"], lyrics.lines.iter().map(|l| l.text.as_str()).collect::<Vec<_>>());
+        assert_eq!(Some(Duration::from_millis(4750)), lyrics.lines[0].start);
+        assert_eq!(Some(1), lyrics.line_at(Duration::from_secs(60)));
+        assert_eq!(None, lyrics.line_at(Duration::from_secs(1)));
+        assert_eq!(lyrics, Lyrics::parse_lrc(&lyrics.to_lrc()));
+
+        // Broken times are skipped rather than trusted
+        let broken = Lyrics::parse_lrc("[offset:-9223372036854775808]\n[00:-1.0]a\n[00:inf]b\n[999999999999999999:00]c\n[00:NaN]d\n[00:01]e");
+        assert_eq!(1, broken.lines.len());
+        assert!(broken.lines[0].start.unwrap() > Duration::from_secs(1));
+
+        let plain = Lyrics::parse_lrc("Just words\nNo times");
+        assert!(!plain.synced);
+        assert_eq!(2, plain.lines.len());
+    }
+
+    #[test]
+    fn tags() {
+        let dir = std::env::temp_dir().join(format!("slib-lyrics-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // ID3v2.3 with a single UTF-8 USLT frame
+        let mut frame = vec![3];
+        frame.extend(b"eng");
+        frame.extend(b"desc\0[00:01.00]Hello");
+        let mut id3 = b"ID3\x03\x00\x00".to_vec();
+        id3.extend([0, 0, 0, (10 + frame.len()) as u8]);
+        id3.extend(b"USLT");
+        id3.extend((frame.len() as u32).to_be_bytes());
+        id3.extend([0, 0]);
+        id3.extend(frame);
+        fs::write(dir.join("song.mp3"), id3).unwrap();
+        let lyrics = embedded(&dir.join("song.mp3")).unwrap();
+        assert_eq!("Hello", lyrics.lines[0].text);
+        assert!(lyrics.synced);
+
+        // FLAC with only a vorbis comment block
+        let comment = b"LYRICS=Plain words";
+        let mut block = Vec::new();
+        block.extend(3u32.to_le_bytes());
+        block.extend(b"lib");
+        block.extend(1u32.to_le_bytes());
+        block.extend((comment.len() as u32).to_le_bytes());
+        block.extend(comment);
+        let mut flac = b"fLaC".to_vec();
+        flac.push(0x84);
+        flac.extend(&(block.len() as u32).to_be_bytes()[1..]);
+        flac.extend(block);
+        fs::write(dir.join("song.flac"), flac).unwrap();
+        assert_eq!(Lyrics::plain("Plain words"), embedded(&dir.join("song.flac")).unwrap());
+
+        // Sidecar files win over tags
+        fs::write(dir.join("song.lrc"), "[00:02.00]Sidecar").unwrap();
+        let cache = LyricsCache::open(dir.join("cache")).unwrap();
+        assert_eq!("Sidecar", cache.find("1", Some(&dir.join("song.flac")), None).unwrap().lines[0].text);
+        assert_eq!("Sidecar", cache.find("1", None, None).unwrap().lines[0].text);
+
+        // Ids that would sanitise to the same name are kept apart
+        cache.insert("a.b", &Lyrics::plain("Dot")).unwrap();
+        cache.insert("a/b", &Lyrics::plain("Slash")).unwrap();
+        assert_eq!(None, cache.get("a_b"));
+        assert_eq!(Lyrics::plain("Dot"), cache.get("a.b").unwrap());
+        assert_eq!(Lyrics::plain("Slash"), cache.get("a/b").unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
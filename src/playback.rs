@@ -0,0 +1,515 @@
+use std::{collections::VecDeque, f32::consts::FRAC_PI_2, fs::File, io::{self, BufWriter, Write}, path::Path, time::Duration};
+
+use crate::{eq::{Band, Equalizer}, replaygain::{GainInfo, ReplayGain}, stretch::{self, TimeStretch}};
+
+/// The layout of decoded audio, every source fed to an `Engine` has to match it
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Format {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl Format {
+    /// How many samples, counting every channel, cover a duration
+    pub fn samples(&self, duration: Duration) -> usize
+    {
+        (duration.as_secs_f64() * self.sample_rate as f64).round() as usize * self.channels as usize
+    }
+
+    pub fn duration(&self, frames: u64) -> Duration
+    {
+        Duration::from_secs_f64(frames as f64 / self.sample_rate as f64)
+    }
+}
+
+/// Decoded audio of one track, as interleaved samples
+pub trait Source: Send {
+    fn format(&self) -> Format;
+    /// Fill as much of `buf` as there is, returning 0 once the track is over
+    fn read(&mut self, buf: &mut [f32]) -> usize;
+}
+
+/// A track already decoded into memory
+pub struct BufferSource {
+    format: Format,
+    samples: Vec<f32>,
+    position: usize,
+}
+
+impl BufferSource {
+    pub fn new(format: Format, samples: Vec<f32>) -> BufferSource
+    {
+        BufferSource { format, samples, position: 0 }
+    }
+}
+
+impl Source for BufferSource {
+    fn format(&self) -> Format {
+        self.format
+    }
+
+    fn read(&mut self, buf: &mut [f32]) -> usize {
+        let len = buf.len().min(self.samples.len() - self.position);
+        buf[..len].copy_from_slice(&self.samples[self.position..self.position + len]);
+        self.position += len;
+        len
+    }
+}
+
+/// Where rendered audio goes, like a sound card
+pub trait Sink {
+    fn write(&mut self, samples: &[f32]) -> io::Result<()>;
+}
+
+/// Keeps every sample, for checking what was played
+impl Sink for Vec<f32> {
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        self.extend_from_slice(samples);
+        Ok(())
+    }
+}
+
+/// Throws the audio away, only counting it
+#[derive(Default)]
+pub struct NullSink {
+    pub samples: u64,
+}
+
+impl Sink for NullSink {
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        self.samples += samples.len() as u64;
+        Ok(())
+    }
+}
+
+/// Writes raw little endian f32 samples to a file
+pub struct FileSink {
+    file: BufWriter<File>,
+}
+
+impl FileSink {
+    pub fn create(path: &Path) -> io::Result<FileSink>
+    {
+        Ok(FileSink { file: BufWriter::new(File::create(path)?) })
+    }
+}
+
+impl Sink for FileSink {
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.file.flush()
+    }
+}
+
+/// Reads a source a chunk at a time, scaled by its ReplayGain
+struct Reader {
+    source: Box<dyn Source>,
+    buf: Vec<f32>,
+    position: usize,
+    len: usize,
+    done: bool,
+    gain: GainInfo,
+    factor: f32,
+}
+
+impl Reader {
+    fn new(source: Box<dyn Source>, gain: GainInfo, replay_gain: &ReplayGain) -> Reader
+    {
+        Reader { source, buf: vec![0.0; 4096], position: 0, len: 0, done: false, gain, factor: replay_gain.factor(&gain) }
+    }
+
+    fn next(&mut self) -> Option<f32>
+    {
+        if self.position == self.len && !self.done {
+            self.len = self.source.read(&mut self.buf);
+            self.position = 0;
+            self.done = self.len == 0;
+        }
+        if self.done {
+            return None;
+        }
+        self.position += 1;
+        Some(self.buf[self.position - 1] * self.factor)
+    }
+}
+
+/// The end of the last track, fading out under the start of the next
+struct Fade {
+    tail: VecDeque<f32>,
+    len: usize,
+    done: usize,
+}
+
+/// Plays a track and the one after it with no gap between them, or crossfaded.
+///
+/// The end of the current track is read ahead by the crossfade length, so
+/// once the track runs out that much is left to lay over the next one.
+/// Without a crossfade the next track starts on the very next sample.
+pub struct Engine {
+    format: Format,
+    current: Option<Reader>,
+    next: Option<Reader>,
+    lookahead: VecDeque<f32>,
+    fade: Option<Fade>,
+    crossfade: Duration,
+    /// Applied after tracks are mixed, so a fade isn't louder or quieter than either track
+    volume: f32,
+    /// Applied to each track as it is read, before the volume
+    replay_gain: ReplayGain,
+    eq: Option<Equalizer>,
+    /// Only there when not playing at normal speed
+    stretch: Option<TimeStretch>,
+    /// Samples of the current track played so far
+    played: u64,
+    advanced: usize,
+}
+
+impl Engine {
+    pub fn new(format: Format) -> Engine
+    {
+        Engine {
+            format,
+            current: None,
+            next: None,
+            lookahead: VecDeque::new(),
+            fade: None,
+            crossfade: Duration::ZERO,
+            volume: 1.0,
+            replay_gain: ReplayGain::default(),
+            eq: None,
+            stretch: None,
+            played: 0,
+            advanced: 0,
+        }
+    }
+
+    pub fn format(&self) -> Format
+    {
+        self.format
+    }
+
+    pub fn crossfade(&self) -> Duration
+    {
+        self.crossfade
+    }
+
+    pub fn volume(&self) -> f32
+    {
+        self.volume
+    }
+
+    /// 1.0 is full scale
+    pub fn set_volume(&mut self, volume: f32)
+    {
+        self.volume = volume.max(0.0);
+    }
+
+    pub fn replay_gain(&self) -> ReplayGain
+    {
+        self.replay_gain
+    }
+
+    /// Takes effect on the tracks already playing too, apart from what has been read ahead for a crossfade.
+    /// Returns false if the preamp or fallback is out of range.
+    pub fn set_replay_gain(&mut self, replay_gain: ReplayGain) -> bool
+    {
+        if !replay_gain.is_valid() {
+            return false;
+        }
+        self.replay_gain = replay_gain;
+        for reader in self.current.iter_mut().chain(self.next.iter_mut()) {
+            reader.factor = replay_gain.factor(&reader.gain);
+        }
+        true
+    }
+
+    pub fn eq(&self) -> &[Band]
+    {
+        self.eq.as_ref().map_or(&[], |eq| eq.bands())
+    }
+
+    /// No bands turns the equalizer off. Returns false if a band doesn't fit the format.
+    pub fn set_eq(&mut self, bands: &[Band]) -> bool
+    {
+        if bands.is_empty() {
+            self.eq = None;
+            return true;
+        }
+        match Equalizer::new(self.format, bands) {
+            Some(eq) => { self.eq = Some(eq); true },
+            None => false,
+        }
+    }
+
+    pub fn rate(&self) -> f32
+    {
+        self.stretch.as_ref().map_or(1.0, |stretch| stretch.rate())
+    }
+
+    /// Play faster or slower keeping the pitch. Returns false if the rate is outside 0.5 to 3.
+    pub fn set_rate(&mut self, rate: f32) -> bool
+    {
+        if !(stretch::MIN_RATE..=stretch::MAX_RATE).contains(&rate) {
+            return false;
+        }
+        match self.stretch.as_mut() {
+            _ if rate == 1.0 => self.stretch = None,
+            Some(stretch) => stretch.set_rate(rate),
+            None => self.stretch = Some(TimeStretch::new(self.format.channels as usize, self.format.sample_rate, rate)),
+        }
+        true
+    }
+
+    /// Zero is gapless
+    pub fn set_crossfade(&mut self, crossfade: Duration)
+    {
+        self.crossfade = crossfade;
+    }
+
+    /// Start a track now, dropping whatever was playing. Returns false if the format doesn't match.
+    pub fn play(&mut self, source: Box<dyn Source>, gain: GainInfo) -> bool
+    {
+        if source.format() != self.format {
+            return false;
+        }
+        self.current = Some(Reader::new(source, gain, &self.replay_gain));
+        self.lookahead.clear();
+        self.fade = None;
+        self.played = 0;
+        true
+    }
+
+    /// Line up the track after the current one. Returns false if the format doesn't match.
+    pub fn preload(&mut self, source: Box<dyn Source>, gain: GainInfo) -> bool
+    {
+        if source.format() != self.format {
+            return false;
+        }
+        self.next = Some(Reader::new(source, gain, &self.replay_gain));
+        true
+    }
+
+    /// Whether the next track should be preloaded
+    pub fn needs_preload(&self) -> bool
+    {
+        self.current.is_some() && self.next.is_none()
+    }
+
+    /// Move to the preloaded track right away
+    pub fn skip(&mut self)
+    {
+        self.current = self.next.take();
+        self.lookahead.clear();
+        self.fade = None;
+        self.played = 0;
+        self.advanced += 1;
+    }
+
+    pub fn stop(&mut self)
+    {
+        self.current = None;
+        self.next = None;
+        self.lookahead.clear();
+        self.fade = None;
+        self.played = 0;
+    }
+
+    pub fn is_playing(&self) -> bool
+    {
+        self.current.is_some() || self.fade.is_some()
+    }
+
+    /// How far into the current track playback is, not counting what the stretcher still holds
+    pub fn position(&self) -> Duration
+    {
+        let latency = self.stretch.as_ref().map_or(0, |stretch| stretch.latency()) as u64;
+        self.format.duration((self.played / self.format.channels as u64).saturating_sub(latency))
+    }
+
+    /// How many times playback moved on to the next track since this was last called
+    pub fn take_advanced(&mut self) -> usize
+    {
+        std::mem::take(&mut self.advanced)
+    }
+
+    /// The next sample of the current track, moving on to the next track once its end has been read ahead
+    fn pull(&mut self) -> Option<f32>
+    {
+        let fade_len = self.format.samples(self.crossfade);
+        loop {
+            let current = self.current.as_mut()?;
+            while self.lookahead.len() <= fade_len {
+                match current.next() {
+                    Some(sample) => self.lookahead.push_back(sample),
+                    None => break,
+                }
+            }
+
+            // Far enough from the end, or nothing to fade into
+            if self.lookahead.len() > fade_len || self.next.is_none() {
+                let sample = self.lookahead.pop_front();
+                match sample {
+                    Some(_) => self.played += 1,
+                    None => self.current = None,
+                }
+                return sample;
+            }
+
+            // What is left of the track fades out under the next one
+            let tail = std::mem::take(&mut self.lookahead);
+            if !tail.is_empty() {
+                self.fade = Some(Fade { len: tail.len(), tail, done: 0 });
+            }
+            self.current = self.next.take();
+            self.played = 0;
+            self.advanced += 1;
+        }
+    }
+
+    /// Fill `out` with interleaved samples, returns how many frames had audio. The rest is silence.
+    pub fn render(&mut self, out: &mut [f32]) -> usize
+    {
+        let channels = self.format.channels as usize;
+        let audio = match self.stretch.take() {
+            Some(mut stretch) => {
+                // Feed the stretcher until it has enough, silence after the end flushes it
+                let mut chunk = vec![0.0; 1024 * channels];
+                while stretch.available() < out.len() / channels {
+                    let audio = self.mix(&mut chunk);
+                    stretch.push(&chunk, audio);
+                }
+                let audio = stretch.pop(out);
+                self.stretch = Some(stretch);
+                audio
+            },
+            None => self.mix(out),
+        };
+        // The silence after the end goes through too, so the filters ring out
+        if let Some(eq) = &mut self.eq {
+            eq.process(out);
+        }
+        audio
+    }
+
+    /// Mix the tracks at normal speed into `out`, returns how many frames had audio
+    fn mix(&mut self, out: &mut [f32]) -> usize
+    {
+        let channels = self.format.channels as usize;
+        let mut written = 0;
+        for (i, sample) in out.iter_mut().enumerate() {
+            let incoming = self.pull();
+            *sample = self.volume * match self.fade.as_mut() {
+                Some(fade) => {
+                    // The same gain for every channel of a frame, equal power so the level holds
+                    let t = (fade.done / channels * channels) as f32 / fade.len as f32;
+                    let outgoing = fade.tail.pop_front().unwrap_or(0.0);
+                    fade.done += 1;
+                    if fade.tail.is_empty() {
+                        self.fade = None;
+                    }
+                    outgoing * (t * FRAC_PI_2).cos() + incoming.unwrap_or(0.0) * (t * FRAC_PI_2).sin()
+                },
+                None => match incoming {
+                    Some(sample) => sample,
+                    None => {
+                        out[i..].fill(0.0);
+                        break;
+                    },
+                },
+            };
+            written = i + 1;
+        }
+        written / channels
+    }
+
+    /// Render a buffer of `frames` into a sink, returns how many frames had audio
+    pub fn play_into(&mut self, sink: &mut dyn Sink, frames: usize) -> io::Result<usize>
+    {
+        let mut buf = vec![0.0; frames * self.format.channels as usize];
+        let played = self.render(&mut buf);
+        sink.write(&buf)?;
+        Ok(played)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMAT: Format = Format { sample_rate: 1000, channels: 1 };
+
+    fn track(value: f32, frames: usize) -> Box<dyn Source> {
+        Box::new(BufferSource::new(FORMAT, vec![value; frames]))
+    }
+
+    #[test]
+    fn gapless() {
+        let mut engine = Engine::new(FORMAT);
+        engine.play(track(1.0, 100), GainInfo::default());
+        assert!(engine.needs_preload());
+        engine.preload(track(0.5, 50), GainInfo::default());
+
+        let mut sink = Vec::new();
+        assert_eq!(64, engine.play_into(&mut sink, 64).unwrap());
+        assert_eq!(Duration::from_millis(64), engine.position());
+        assert_eq!(86, engine.play_into(&mut sink, 100).unwrap());
+
+        // Straight from one track into the next on the same sample
+        assert!(sink[..100].iter().all(|&s| s == 1.0));
+        assert!(sink[100..150].iter().all(|&s| s == 0.5));
+        assert!(sink[150..].iter().all(|&s| s == 0.0));
+        assert_eq!(1, engine.take_advanced());
+        assert!(!engine.is_playing());
+    }
+
+    #[test]
+    fn crossfade() {
+        let mut engine = Engine::new(FORMAT);
+        engine.set_crossfade(Duration::from_millis(20));
+        engine.play(track(1.0, 100), GainInfo::default());
+        engine.preload(track(1.0, 50), GainInfo::default());
+
+        let mut sink = NullSink::default();
+        assert_eq!(130, engine.play_into(&mut sink, 200).unwrap());
+        assert_eq!(200, sink.samples);
+
+        // The last 20 ms of the first track overlap the start of the second
+        let mut engine = Engine::new(FORMAT);
+        engine.set_crossfade(Duration::from_millis(20));
+        engine.play(track(1.0, 100), GainInfo::default());
+        engine.preload(track(0.0, 50), GainInfo::default());
+        let mut out = vec![0.0; 130];
+        engine.render(&mut out);
+        assert!(out[..80].iter().all(|&s| s == 1.0));
+        assert!(out[80..100].windows(2).all(|w| w[0] > w[1]));
+        assert_eq!(0.0, out[100]);
+        assert_eq!(Duration::from_millis(50), engine.position());
+    }
+
+    #[test]
+    fn replay_gain() {
+        use crate::replaygain::ReplayGainMode;
+
+        let mut engine = Engine::new(FORMAT);
+        engine.set_volume(0.5);
+        assert!(engine.set_replay_gain(ReplayGain { mode: ReplayGainMode::Track, ..ReplayGain::default() }));
+        let gain = GainInfo { track_gain: Some(-6.02), ..GainInfo::default() };
+        engine.play(track(1.0, 100), gain);
+        engine.preload(track(1.0, 100), GainInfo::default());
+
+        // Half from the gain, then half again from the volume
+        let mut out = vec![0.0; 150];
+        engine.render(&mut out[..50]);
+        assert!(out[..50].iter().all(|&s| (s - 0.25).abs() < 0.001));
+
+        // Switching it off reaches the track already playing, the next track has no gain to apply
+        assert!(engine.set_replay_gain(ReplayGain::default()));
+        engine.render(&mut out[50..]);
+        assert!(out[50..].iter().all(|&s| s == 0.5));
+
+        assert!(!engine.set_replay_gain(ReplayGain { preamp: f32::NAN, ..ReplayGain::default() }));
+        assert_eq!(ReplayGain::default(), engine.replay_gain());
+    }
+}
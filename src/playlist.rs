@@ -0,0 +1,547 @@
+use std::{fs, io, path::PathBuf, time::{SystemTime, UNIX_EPOCH}};
+use serde::{Deserialize, Serialize};
+
+use crate::{smart::Rule, Item, SlibError, SongInfo};
+
+/// How many pairs of songs a merge may compare, past that lists that differ a lot are a conflict
+const MAX_DIFF: usize = 4_000_000;
+
+/// A playlist kept on disk by the daemon
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct Playlist {
+    pub item: Item,
+    pub songs: Vec<Item>,
+    #[serde(default)]
+    pub comment: String,
+    #[serde(default)]
+    pub public: bool,
+    /// The rule of a smart playlist, whose songs are picked by the daemon
+    #[serde(default)]
+    pub rule: Option<String>,
+    /// Bumped on every local edit
+    pub revision: u64,
+    /// The revision that was last synced with the server
+    pub synced_revision: u64,
+    /// The songs as of the last sync, used as the common ancestor when merging
+    pub base: Option<Vec<Item>>,
+    /// The name, comment or public flag changed since the last sync.
+    /// These aren't merged, the local ones replace the server's.
+    #[serde(default)]
+    pub metadata_dirty: bool,
+}
+
+impl Playlist {
+    /// Whether there are local edits that the server hasn't seen
+    pub fn is_dirty(&self) -> bool {
+        self.revision != self.synced_revision
+    }
+
+    /// The `updatePlaylist` parameters that set the name, comment and public flag
+    pub fn update_params(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("playlistId", self.item.id.clone()),
+            ("name", self.item.name.clone()),
+            ("comment", self.comment.clone()),
+            ("public", self.public.to_string()),
+        ]
+    }
+}
+
+/// The outcome of syncing a local playlist against the server copy
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub enum SyncResult {
+    /// Both copies already agree
+    Unchanged,
+    /// Only the local copy changed, the server needs to take it
+    Uploaded,
+    /// Only the server copy changed, the local copy took it
+    Downloaded,
+    /// Both copies changed in different places, the local copy took both and the server needs to take it
+    Merged(Vec<Item>),
+    /// Both copies edited the same songs since the last sync
+    Conflict{local: Vec<Item>, remote: Vec<Item>},
+    /// There is no local playlist by that id
+    NotFound,
+}
+
+/// Three-way merge of a playlist against the last synced copy
+pub fn merge(base: Option<&[Item]>, local: &[Item], remote: Option<&[Item]>) -> SyncResult {
+    match (base, remote) {
+        // The server has never seen this playlist
+        (_, None) => SyncResult::Uploaded,
+        // Never synced, so only identical copies can be reconciled
+        (None, Some(remote)) if local == remote => SyncResult::Unchanged,
+        (None, Some(remote)) => SyncResult::Conflict{local: local.to_vec(), remote: remote.to_vec()},
+        (Some(base), Some(remote)) => {
+            let local_changed = local != base;
+            let remote_changed = remote != base;
+            match (local_changed, remote_changed) {
+                (false, false) => SyncResult::Unchanged,
+                (true, false) => SyncResult::Uploaded,
+                (false, true) => SyncResult::Downloaded,
+                // Both sides made the same edit
+                (true, true) if local == remote => SyncResult::Unchanged,
+                (true, true) => {
+                    let edits = diff(base, local).zip(diff(base, remote));
+                    match edits.and_then(|(local_edits, remote_edits)| apply_edits(base, local_edits, remote_edits)) {
+                        // One side's edits were all made on the other too
+                        Some(merged) if merged == remote => SyncResult::Downloaded,
+                        Some(merged) if merged == local => SyncResult::Uploaded,
+                        Some(merged) => SyncResult::Merged(merged),
+                        None => SyncResult::Conflict{local: local.to_vec(), remote: remote.to_vec()},
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// An edit to the base list, the songs in `start..end` replaced by `songs`
+#[derive(PartialEq)]
+struct Edit<'a> {
+    start: usize,
+    end: usize,
+    songs: &'a [Item],
+}
+
+/// The edits that turn `base` into `other`, keeping their longest common subsequence.
+/// None if the lists are too long and too different to compare.
+fn diff<'a>(base: &[Item], other: &'a [Item]) -> Option<Vec<Edit<'a>>>
+{
+    let prefix = base.iter().zip(other).take_while(|(a, b)| a == b).count();
+    let suffix = base[prefix..].iter().rev().zip(other[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let old = &base[prefix..base.len() - suffix];
+    let new = &other[prefix..other.len() - suffix];
+    if old.len().saturating_mul(new.len()) > MAX_DIFF {
+        return None;
+    }
+
+    // The length of the common subsequence of old[i..] and new[j..] is at i * width + j
+    let width = new.len() + 1;
+    let mut lengths = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i * width + j] = match old[i] == new[j] {
+                true => lengths[(i + 1) * width + j + 1] + 1,
+                false => lengths[(i + 1) * width + j].max(lengths[i * width + j + 1]),
+            };
+        }
+    }
+
+    let mut edits = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let (mut from_i, mut from_j) = (0, 0);
+    let mut push = |from_i: usize, from_j: usize, i: usize, j: usize| {
+        if (from_i, from_j) != (i, j) {
+            edits.push(Edit { start: prefix + from_i, end: prefix + i, songs: &new[from_j..j] });
+        }
+    };
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            push(from_i, from_j, i, j);
+            i += 1;
+            j += 1;
+            (from_i, from_j) = (i, j);
+        }
+        else if j < new.len() && (i == old.len() || lengths[i * width + j + 1] >= lengths[(i + 1) * width + j]) {
+            j += 1;
+        }
+        else {
+            i += 1;
+        }
+    }
+    push(from_i, from_j, i, j);
+    Some(edits)
+}
+
+/// Apply the edits of both sides to the base, None if they touch the same songs
+fn apply_edits(base: &[Item], local: Vec<Edit>, remote: Vec<Edit>) -> Option<Vec<Item>>
+{
+    // Replacing the same songs, or adding songs at the same place
+    let overlap = |a: &Edit, b: &Edit| {
+        (a.start < b.end && b.start < a.end) || (a.start == a.end && b.start == b.end && a.start == b.start)
+    };
+    let mut edits = local;
+    for edit in remote {
+        if edits.contains(&edit) {
+            continue;
+        }
+        if edits.iter().any(|e| overlap(e, &edit)) {
+            return None;
+        }
+        edits.push(edit);
+    }
+    // Additions go before removals that start at the same place
+    edits.sort_by_key(|e| (e.start, e.end));
+
+    let mut merged = Vec::with_capacity(base.len());
+    let mut pos = 0;
+    for edit in edits {
+        merged.extend_from_slice(&base[pos..edit.start]);
+        merged.extend_from_slice(edit.songs);
+        pos = edit.end;
+    }
+    merged.extend_from_slice(&base[pos..]);
+    Some(merged)
+}
+
+/// Local playlists stored as one json file each in a directory
+pub struct PlaylistStore {
+    dir: PathBuf,
+}
+
+impl PlaylistStore {
+    /// Open the store, creating the directory if needed
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<PlaylistStore>
+    {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(PlaylistStore{dir})
+    }
+
+    /// Ids from the server are only trusted as file names when they can't leave the directory
+    fn path(&self, id: &str) -> PathBuf {
+        if !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            self.dir.join(format!("{id}.json"))
+        }
+        else {
+            self.dir.join(format!("{:x}.json", md5::compute(id)))
+        }
+    }
+
+    /// Return every stored playlist
+    pub fn list(&self) -> io::Result<Vec<Playlist>>
+    {
+        let mut playlists = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "json") {
+                let data = fs::read_to_string(path)?;
+                playlists.push(serde_json::from_str(&data)?);
+            }
+        }
+        Ok(playlists)
+    }
+
+    /// Load a playlist by id
+    pub fn load(&self, id: &str) -> io::Result<Option<Playlist>>
+    {
+        match fs::read_to_string(self.path(id)) {
+            Ok(data) => Ok(Some(serde_json::from_str(&data)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write a playlist to disk
+    pub fn save(&self, playlist: &Playlist) -> io::Result<()>
+    {
+        // Write to the side and rename so a crash never leaves half a file
+        let path = self.path(&playlist.item.id);
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string(playlist)?)?;
+        fs::rename(tmp, path)
+    }
+
+    /// Delete a playlist, returns false if it didn't exist
+    pub fn remove(&self, id: &str) -> io::Result<bool>
+    {
+        match fs::remove_file(self.path(id)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Create a new empty local playlist
+    pub fn create(&self, name: String) -> io::Result<Playlist>
+    {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let playlist = Playlist {
+            item: Item { name, id: format!("local-{nanos}"), image_path: String::new() },
+            songs: Vec::new(),
+            comment: String::new(),
+            public: false,
+            rule: None,
+            revision: 1,
+            synced_revision: 0,
+            base: None,
+            metadata_dirty: true,
+        };
+        self.save(&playlist)?;
+        Ok(playlist)
+    }
+
+    /// Create a smart playlist and fill it from the library
+    pub fn create_smart(&self, name: String, rule: String, library: &[(Item, SongInfo)]) -> Result<Playlist, SlibError>
+    {
+        let songs = Rule::parse(&rule)?.apply(library);
+        let mut playlist = self.create(name).map_err(SlibError::Io)?;
+        playlist.rule = Some(rule);
+        playlist.songs = songs;
+        self.save(&playlist).map_err(SlibError::Io)?;
+        Ok(playlist)
+    }
+
+    /// Re-evaluate a smart playlist against the library
+    pub fn refresh(&self, id: &str, library: &[(Item, SongInfo)]) -> Result<bool, SlibError>
+    {
+        let Some(mut playlist) = self.load(id).map_err(SlibError::Io)? else { return Ok(false) };
+        let Some(rule) = &playlist.rule else { return Ok(false) };
+        // Only a change in the songs is a new revision, not a reshuffle of the same ones
+        let songs = Rule::parse(rule)?.reapply(library, &playlist.songs);
+        if songs != playlist.songs {
+            playlist.songs = songs;
+            playlist.revision += 1;
+            self.save(&playlist).map_err(SlibError::Io)?;
+        }
+        Ok(true)
+    }
+
+    /// Re-evaluate every smart playlist, as `fetch_playlists` should
+    pub fn refresh_all(&self, library: &[(Item, SongInfo)]) -> Result<(), SlibError>
+    {
+        for playlist in self.list().map_err(SlibError::Io)? {
+            if playlist.rule.is_some() {
+                self.refresh(&playlist.item.id, library)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply a local edit to a playlist and bump its revision
+    pub fn edit<F>(&self, id: &str, f: F) -> io::Result<bool>
+    where F: FnOnce(&mut Playlist) -> bool
+    {
+        let Some(mut playlist) = self.load(id)? else { return Ok(false) };
+        if !f(&mut playlist) {
+            return Ok(false);
+        }
+        playlist.revision += 1;
+        self.save(&playlist)?;
+        Ok(true)
+    }
+
+    /// Add a song at a position in a playlist, or at the end if there is none
+    pub fn add_to(&self, id: &str, song: Item, position: Option<usize>) -> io::Result<bool>
+    {
+        self.edit(id, |p| {
+            match position {
+                Some(index) if index > p.songs.len() => false,
+                Some(index) => { p.songs.insert(index, song); true },
+                None => { p.songs.push(song); true },
+            }
+        })
+    }
+
+    /// Remove the first occurrence of a song from a playlist
+    pub fn remove_from(&self, id: &str, song: &Item) -> io::Result<bool>
+    {
+        self.edit(id, |p| {
+            match p.songs.iter().position(|s| s == song) {
+                Some(index) => { p.songs.remove(index); true },
+                None => false,
+            }
+        })
+    }
+
+    /// Remove the song at an index from a playlist
+    pub fn remove_at(&self, id: &str, index: usize) -> io::Result<bool>
+    {
+        self.edit(id, |p| {
+            if index < p.songs.len() { p.songs.remove(index); true } else { false }
+        })
+    }
+
+    /// Move a song within a playlist
+    pub fn move_song(&self, id: &str, from: usize, to: usize) -> io::Result<bool>
+    {
+        self.edit(id, |p| {
+            if from >= p.songs.len() || to >= p.songs.len() {
+                return false;
+            }
+            let song = p.songs.remove(from);
+            p.songs.insert(to, song);
+            true
+        })
+    }
+
+    /// Rename a playlist
+    pub fn rename(&self, id: &str, name: String) -> io::Result<bool>
+    {
+        self.edit(id, |p| { p.item.name = name; p.metadata_dirty = true; true })
+    }
+
+    /// Set the comment on a playlist
+    pub fn set_comment(&self, id: &str, comment: String) -> io::Result<bool>
+    {
+        self.edit(id, |p| { p.comment = comment; p.metadata_dirty = true; true })
+    }
+
+    /// Set whether a playlist is shared with other users
+    pub fn set_public(&self, id: &str, public: bool) -> io::Result<bool>
+    {
+        self.edit(id, |p| { p.public = public; p.metadata_dirty = true; true })
+    }
+
+    /// Merge a playlist with the server copy.
+    ///
+    /// Server changes are applied locally. When the result is `Uploaded` or `Merged` the
+    /// daemon should push the local songs, and the metadata with `update_params`,
+    /// then call `mark_synced`. Local metadata edits alone also make it `Uploaded`.
+    pub fn sync(&self, id: &str, remote: Option<&[Item]>) -> io::Result<SyncResult>
+    {
+        let Some(mut playlist) = self.load(id)? else { return Ok(SyncResult::NotFound) };
+
+        let result = merge(playlist.base.as_deref(), &playlist.songs, remote);
+        match (&result, remote) {
+            (SyncResult::Downloaded, Some(remote)) | (SyncResult::Unchanged, Some(remote)) => {
+                playlist.songs = remote.to_vec();
+                playlist.base = Some(remote.to_vec());
+                if playlist.metadata_dirty {
+                    self.save(&playlist)?;
+                    return Ok(SyncResult::Uploaded);
+                }
+                playlist.synced_revision = playlist.revision;
+                self.save(&playlist)?;
+            },
+            // The server still has its own copy until the daemon pushes the merge
+            (SyncResult::Merged(songs), Some(remote)) => {
+                playlist.songs = songs.clone();
+                playlist.base = Some(remote.to_vec());
+                self.save(&playlist)?;
+            },
+            _ => {},
+        }
+        Ok(result)
+    }
+
+    /// Record that the server now has the local copy of a playlist
+    pub fn mark_synced(&self, id: &str) -> io::Result<bool>
+    {
+        let Some(mut playlist) = self.load(id)? else { return Ok(false) };
+        playlist.base = Some(playlist.songs.clone());
+        playlist.synced_revision = playlist.revision;
+        playlist.metadata_dirty = false;
+        self.save(&playlist)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(id: &str) -> Item {
+        Item { name: format!("Song {id}"), id: id.to_string(), image_path: String::new() }
+    }
+
+    #[test]
+    fn three_way_merge() {
+        let base = vec![song("1"), song("2")];
+        let edited = vec![song("1"), song("2"), song("3")];
+        let other = vec![song("2")];
+
+        assert_eq!(SyncResult::Unchanged, merge(Some(&base), &base, Some(&base)));
+        assert_eq!(SyncResult::Uploaded, merge(Some(&base), &edited, Some(&base)));
+        assert_eq!(SyncResult::Downloaded, merge(Some(&base), &base, Some(&edited)));
+        assert_eq!(SyncResult::Unchanged, merge(Some(&base), &edited, Some(&edited)));
+        assert_eq!(SyncResult::Uploaded, merge(None, &edited, None));
+
+        // Edits in different places are both kept
+        assert_eq!(SyncResult::Merged(vec![song("2"), song("3")]), merge(Some(&base), &edited, Some(&other)));
+        let base = vec![song("1"), song("2"), song("3"), song("4")];
+        let local = vec![song("0"), song("1"), song("3"), song("4")];
+        let remote = vec![song("1"), song("2"), song("4"), song("3"), song("5")];
+        assert_eq!(
+            SyncResult::Merged(vec![song("0"), song("1"), song("4"), song("3"), song("5")]),
+            merge(Some(&base), &local, Some(&remote))
+        );
+        // One side's edits already made on the other
+        let both = vec![song("0"), song("1"), song("3"), song("4"), song("5")];
+        assert_eq!(SyncResult::Downloaded, merge(Some(&base), &local, Some(&both)));
+
+        // Edits to the same songs, or additions at the same place, can't be merged
+        let removed = vec![song("1"), song("3"), song("4")];
+        let replaced = vec![song("1"), song("9"), song("3"), song("4")];
+        assert!(matches!(merge(Some(&base), &removed, Some(&replaced)), SyncResult::Conflict{..}));
+        let appended = vec![song("1"), song("2"), song("3"), song("4"), song("6")];
+        let appended_other = vec![song("1"), song("2"), song("3"), song("4"), song("7")];
+        assert!(matches!(merge(Some(&base), &appended, Some(&appended_other)), SyncResult::Conflict{..}));
+    }
+
+    #[test]
+    fn store_sync() {
+        let dir = std::env::temp_dir().join(format!("slib-playlists-{}", std::process::id()));
+        let store = PlaylistStore::open(&dir).unwrap();
+
+        let id = store.create("Mix".to_string()).unwrap().item.id;
+        assert!(store.add_to(&id, song("1"), None).unwrap());
+        assert_eq!(SyncResult::Uploaded, store.sync(&id, None).unwrap());
+        assert!(store.load(&id).unwrap().unwrap().is_dirty());
+        store.mark_synced(&id).unwrap();
+
+        // The server gained a song while we were offline
+        let remote = vec![song("1"), song("2")];
+        assert_eq!(SyncResult::Downloaded, store.sync(&id, Some(&remote)).unwrap());
+        assert_eq!(remote, store.load(&id).unwrap().unwrap().songs);
+
+        // A rename has to reach the server even though the songs agree
+        assert!(store.rename(&id, "Renamed".to_string()).unwrap());
+        assert_eq!(SyncResult::Uploaded, store.sync(&id, Some(&remote)).unwrap());
+        let playlist = store.load(&id).unwrap().unwrap();
+        assert!(playlist.is_dirty());
+        assert!(playlist.update_params().contains(&("name", "Renamed".to_string())));
+        store.mark_synced(&id).unwrap();
+        assert_eq!(SyncResult::Unchanged, store.sync(&id, Some(&remote)).unwrap());
+
+        // Both sides edit different songs, the local copy takes the merge until it is pushed
+        assert!(store.add_to(&id, song("3"), None).unwrap());
+        let remote = vec![song("2")];
+        assert_eq!(SyncResult::Merged(vec![song("2"), song("3")]), store.sync(&id, Some(&remote)).unwrap());
+        let playlist = store.load(&id).unwrap().unwrap();
+        assert_eq!(vec![song("2"), song("3")], playlist.songs);
+        assert!(playlist.is_dirty());
+        store.mark_synced(&id).unwrap();
+
+        // Now both sides edit the same song
+        assert!(store.remove_from(&id, &song("2")).unwrap());
+        assert!(matches!(store.sync(&id, Some(&[song("4"), song("3")])).unwrap(), SyncResult::Conflict{..}));
+
+        assert!(store.remove(&id).unwrap());
+
+        // An id can't reach outside the store
+        let outside = Playlist { item: song("../outside"), ..store.create("Escape".to_string()).unwrap() };
+        store.save(&outside).unwrap();
+        assert!(!dir.join("../outside.json").exists());
+        assert_eq!(Some(outside), store.load("../outside").unwrap());
+        assert!(store.remove("../outside").unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn store_edits() {
+        let dir = std::env::temp_dir().join(format!("slib-playlist-edits-{}", std::process::id()));
+        let store = PlaylistStore::open(&dir).unwrap();
+
+        let id = store.create("Mix".to_string()).unwrap().item.id;
+        store.add_to(&id, song("1"), None).unwrap();
+        store.add_to(&id, song("2"), None).unwrap();
+        store.add_to(&id, song("1"), Some(0)).unwrap();
+        assert!(!store.add_to(&id, song("3"), Some(9)).unwrap());
+
+        // Only the duplicate at the front should go
+        assert!(store.remove_at(&id, 0).unwrap());
+        assert!(store.move_song(&id, 1, 0).unwrap());
+        assert!(store.rename(&id, "Renamed".to_string()).unwrap());
+        assert!(store.set_public(&id, true).unwrap());
+
+        let playlist = store.load(&id).unwrap().unwrap();
+        assert_eq!(vec![song("2"), song("1")], playlist.songs);
+        assert_eq!("Renamed", playlist.item.name);
+        assert!(playlist.public);
+        assert_eq!(8, playlist.revision);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,473 @@
+use std::{collections::BTreeMap, time::Duration};
+use serde::{Deserialize, Serialize};
+
+use crate::{Item, SlibError, SongInfo};
+
+/// Playlist file formats that can be imported and exported
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PlaylistFormat {
+    M3u,
+    /// M3U encoded as UTF-8
+    M3u8,
+    Xspf,
+    Pls,
+}
+
+/// A single entry in a playlist file
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Default)]
+pub struct Entry {
+    pub location: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration: Option<Duration>,
+}
+
+impl Entry {
+    /// Build an entry for exporting a song, the location is the song id
+    pub fn from_song(song: &Item, info: &SongInfo) -> Entry
+    {
+        Entry {
+            location: song.id.clone(),
+            title: Some(song.name.clone()),
+            artist: Some(info.artist.clone()),
+            album: Some(info.album.name.clone()),
+            duration: Some(info.length),
+        }
+    }
+}
+
+/// The contents of a playlist file
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Default)]
+pub struct PlaylistFile {
+    pub name: Option<String>,
+    pub entries: Vec<Entry>,
+}
+
+/// The outcome of importing a playlist file
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct ImportResult {
+    /// The local playlist that was created
+    pub playlist: Item,
+    /// Entries that couldn't be matched to a song
+    pub unresolved: Vec<Entry>,
+}
+
+/// Parse a playlist file
+pub fn parse(format: PlaylistFormat, data: &str) -> Result<PlaylistFile, SlibError>
+{
+    match format {
+        PlaylistFormat::M3u | PlaylistFormat::M3u8 => Ok(parse_m3u(data)),
+        PlaylistFormat::Pls => parse_pls(data),
+        PlaylistFormat::Xspf => parse_xspf(data),
+    }
+}
+
+/// Write a playlist file
+pub fn write(format: PlaylistFormat, file: &PlaylistFile) -> String
+{
+    match format {
+        PlaylistFormat::M3u | PlaylistFormat::M3u8 => write_m3u(file),
+        PlaylistFormat::Pls => write_pls(file),
+        PlaylistFormat::Xspf => write_xspf(file),
+    }
+}
+
+fn parse_m3u(data: &str) -> PlaylistFile
+{
+    let mut file = PlaylistFile::default();
+    let mut next = Entry::default();
+
+    for line in data.lines().map(str::trim) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<seconds>,<artist> - <title>
+            let (seconds, display) = info.split_once(',').unwrap_or((info, ""));
+            next.duration = parse_seconds(seconds);
+            let (artist, title) = split_display(display);
+            next.artist = artist;
+            next.title = title;
+        }
+        else if let Some(album) = line.strip_prefix("#EXTALB:") {
+            next.album = Some(album.trim().to_string());
+        }
+        else if let Some(name) = line.strip_prefix("#PLAYLIST:") {
+            file.name = Some(name.trim().to_string());
+        }
+        else if !line.is_empty() && !line.starts_with('#') {
+            next.location = line.to_string();
+            file.entries.push(std::mem::take(&mut next));
+        }
+    }
+    file
+}
+
+fn write_m3u(file: &PlaylistFile) -> String
+{
+    let mut out = String::from("#EXTM3U\n");
+    if let Some(name) = &file.name {
+        out += &format!("#PLAYLIST:{name}\n");
+    }
+    for entry in &file.entries {
+        let seconds = entry.duration.map_or(-1, |d| d.as_secs() as i64);
+        out += &format!("#EXTINF:{seconds},{}\n", join_display(entry));
+        if let Some(album) = &entry.album {
+            out += &format!("#EXTALB:{album}\n");
+        }
+        out += &entry.location;
+        out += "\n";
+    }
+    out
+}
+
+fn parse_pls(data: &str) -> Result<PlaylistFile, SlibError>
+{
+    let mut lines = data.lines().map(str::trim).filter(|l| !l.is_empty());
+    if !lines.next().is_some_and(|l| l.eq_ignore_ascii_case("[playlist]")) {
+        return Err(SlibError::InvalidPlaylistFile(String::from("missing [playlist] header")));
+    }
+
+    // Keyed by the number in the file, which can be anything, so gaps don't take up space
+    let mut entries: BTreeMap<usize, Entry> = BTreeMap::new();
+    for line in lines {
+        let Some((key, value)) = line.split_once('=') else { continue };
+
+        // Keys look like File1, Title1, Length1
+        let split = key.find(|c: char| c.is_ascii_digit()).unwrap_or(key.len());
+        let (field, index) = key.split_at(split);
+        let Ok(index) = index.parse::<usize>() else { continue };
+        if index == 0 {
+            continue;
+        }
+        let entry = entries.entry(index).or_default();
+
+        match field.to_ascii_lowercase().as_str() {
+            "file" => entry.location = value.to_string(),
+            "title" => {
+                let (artist, title) = split_display(value);
+                entry.artist = artist;
+                entry.title = title;
+            },
+            "length" => entry.duration = parse_seconds(value),
+            _ => {},
+        }
+    }
+    let entries = entries.into_values().filter(|e| !e.location.is_empty()).collect();
+    Ok(PlaylistFile{name: None, entries})
+}
+
+fn write_pls(file: &PlaylistFile) -> String
+{
+    let mut out = String::from("[playlist]\n");
+    for (i, entry) in file.entries.iter().enumerate() {
+        let n = i + 1;
+        let seconds = entry.duration.map_or(-1, |d| d.as_secs() as i64);
+        out += &format!("File{n}={}\nTitle{n}={}\nLength{n}={seconds}\n", entry.location, join_display(entry));
+    }
+    out += &format!("NumberOfEntries={}\nVersion=2\n", file.entries.len());
+    out
+}
+
+fn parse_xspf(data: &str) -> Result<PlaylistFile, SlibError>
+{
+    let Some(start) = data.find("<trackList") else {
+        return Err(SlibError::InvalidPlaylistFile(String::from("missing <trackList>")));
+    };
+
+    // The playlist title is the only one that comes before the track list
+    let name = xml_text(&data[..start], "title");
+
+    let mut entries = Vec::new();
+    let mut rest = &data[start..];
+    while let Some(open) = find_tag(rest, "track") {
+        let Some(close) = rest[open..].find("</track>") else {
+            return Err(SlibError::InvalidPlaylistFile(String::from("unclosed <track>")));
+        };
+        let track = &rest[open..open + close];
+        entries.push(Entry {
+            location: xml_text(track, "location").unwrap_or_default(),
+            title: xml_text(track, "title"),
+            artist: xml_text(track, "creator"),
+            album: xml_text(track, "album"),
+            duration: xml_text(track, "duration")
+                .and_then(|d| d.parse::<u64>().ok())
+                .map(Duration::from_millis),
+        });
+        rest = &rest[open + close + "</track>".len()..];
+    }
+    Ok(PlaylistFile{name, entries})
+}
+
+fn write_xspf(file: &PlaylistFile) -> String
+{
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n");
+    if let Some(name) = &file.name {
+        out += &format!("  <title>{}</title>\n", xml_escape(name));
+    }
+    out += "  <trackList>\n";
+    for entry in &file.entries {
+        out += "    <track>\n";
+        out += &format!("      <location>{}</location>\n", xml_escape(&entry.location));
+        let fields = [("title", &entry.title), ("creator", &entry.artist), ("album", &entry.album)];
+        for (tag, value) in fields {
+            if let Some(value) = value {
+                out += &format!("      <{tag}>{}</{tag}>\n", xml_escape(value));
+            }
+        }
+        if let Some(duration) = entry.duration {
+            out += &format!("      <duration>{}</duration>\n", duration.as_millis());
+        }
+        out += "    </track>\n";
+    }
+    out += "  </trackList>\n</playlist>\n";
+    out
+}
+
+/// Find the text of the first `<tag>` in a fragment of xml
+/// Where the next `<tag>` or `<tag attributes...>` opens, skipping longer tags that start the same like `<trackList>`
+fn find_tag(xml: &str, tag: &str) -> Option<usize>
+{
+    let open = format!("<{tag}");
+    let mut from = 0;
+    while let Some(found) = xml[from..].find(&open) {
+        let at = from + found;
+        match xml[at + open.len()..].chars().next() {
+            Some(c) if c == '>' || c.is_whitespace() => return Some(at),
+            _ => from = at + open.len(),
+        }
+    }
+    None
+}
+
+fn xml_text(xml: &str, tag: &str) -> Option<String>
+{
+    let open = format!("<{tag}>");
+    let close = format!("</{tag}>");
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find(&close)? + start;
+    Some(xml_unescape(xml[start..end].trim()))
+}
+
+fn xml_escape(text: &str) -> String
+{
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn xml_unescape(text: &str) -> String
+{
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out += &rest[..amp];
+        rest = &rest[amp..];
+        let Some(semi) = rest.find(';') else { break };
+        let decoded = match &rest[1..semi] {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            e if e.starts_with("#x") => u32::from_str_radix(&e[2..], 16).ok().and_then(char::from_u32),
+            e if e.starts_with('#') => e[1..].parse().ok().and_then(char::from_u32),
+            _ => None,
+        };
+        match decoded {
+            Some(c) => { out.push(c); rest = &rest[semi + 1..]; },
+            None => { out.push('&'); rest = &rest[1..]; },
+        }
+    }
+    out + rest
+}
+
+fn parse_seconds(text: &str) -> Option<Duration>
+{
+    text.trim().parse::<u64>().ok().map(Duration::from_secs)
+}
+
+/// Split an "Artist - Title" display string
+fn split_display(display: &str) -> (Option<String>, Option<String>)
+{
+    let display = display.trim();
+    match display.split_once(" - ") {
+        Some((artist, title)) => (Some(artist.trim().to_string()), Some(title.trim().to_string())),
+        None if display.is_empty() => (None, None),
+        None => (None, Some(display.to_string())),
+    }
+}
+
+fn join_display(entry: &Entry) -> String
+{
+    match (&entry.artist, &entry.title) {
+        (Some(artist), Some(title)) => format!("{artist} - {title}"),
+        (None, Some(title)) => title.clone(),
+        _ => String::new(),
+    }
+}
+
+/// Lowercase and drop everything but letters and digits
+fn normalize(text: &str) -> String
+{
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The title of an entry, falling back to the file name of its location
+fn entry_title(entry: &Entry) -> Option<String>
+{
+    if let Some(title) = &entry.title {
+        return Some(normalize(title));
+    }
+    let file = entry.location.rsplit(['/', '\\']).next()?;
+    let stem = file.rsplit_once('.').map_or(file, |(stem, _)| stem);
+    // Drop a leading track number like "01 - "
+    let number = stem.len() - stem.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let stem = match number {
+        0 => stem,
+        _ => ["- ", " - ", ". ", "-", "_"].iter()
+            .find_map(|sep| stem[number..].strip_prefix(sep))
+            .unwrap_or(stem),
+    };
+    let (_, title) = split_display(stem);
+    title.map(|t| normalize(&t))
+}
+
+/// Best-effort match of a playlist entry against the known songs
+pub fn match_entry<'a>(entry: &Entry, songs: &'a [(Item, SongInfo)]) -> Option<&'a Item>
+{
+    // Files we exported ourselves point straight at the id
+    if let Some((song, _)) = songs.iter().find(|(song, _)| song.id == entry.location) {
+        return Some(song);
+    }
+
+    let title = entry_title(entry)?;
+    let artist = entry.artist.as_deref().map(normalize);
+    let album = entry.album.as_deref().map(normalize);
+
+    let mut best: Option<(u8, &Item)> = None;
+    for (song, info) in songs {
+        if normalize(&song.name) != title {
+            continue;
+        }
+        let mut score = 1;
+        if let Some(artist) = &artist {
+            if *artist != normalize(&info.artist) {
+                continue;
+            }
+            score += 2;
+        }
+        if album.as_ref().is_some_and(|a| *a == normalize(&info.album.name)) {
+            score += 1;
+        }
+        if let Some(duration) = entry.duration {
+            let difference = duration.abs_diff(info.length);
+            if difference > Duration::from_secs(10) {
+                continue;
+            }
+            if difference <= Duration::from_secs(3) {
+                score += 1;
+            }
+        }
+        if best.is_none_or(|(s, _)| score > s) {
+            best = Some((score, song));
+        }
+    }
+    best.map(|(_, song)| song)
+}
+
+/// Match every entry of a playlist file, returning the songs and the entries that didn't match
+pub fn resolve(file: &PlaylistFile, songs: &[(Item, SongInfo)]) -> (Vec<Item>, Vec<Entry>)
+{
+    let mut resolved = Vec::new();
+    let mut unresolved = Vec::new();
+    for entry in &file.entries {
+        match match_entry(entry, songs) {
+            Some(song) => resolved.push(song.clone()),
+            None => unresolved.push(entry.clone()),
+        }
+    }
+    (resolved, unresolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(id: &str, name: &str, artist: &str, secs: u64) -> (Item, SongInfo) {
+        (
+            Item { name: name.to_string(), id: id.to_string(), image_path: String::new() },
+            SongInfo {
+                length: Duration::from_secs(secs),
+                album: Item { name: "Kind of Blue".to_string(), id: "a1".to_string(), image_path: String::new() },
+                artist: artist.to_string(),
+                genre: None,
+                year: None,
+                rating: None,
+                starred: false,
+            },
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let so_what = song("1", "So What", "Miles Davis", 562);
+        let file = PlaylistFile {
+            name: Some("Jazz & Blues".to_string()),
+            entries: vec![
+                Entry::from_song(&so_what.0, &so_what.1),
+                Entry { location: "music/02 - Blue in Green.flac".to_string(), ..Default::default() },
+            ],
+        };
+
+        for format in [PlaylistFormat::M3u8, PlaylistFormat::Xspf, PlaylistFormat::Pls] {
+            let parsed = parse(format, &write(format, &file)).unwrap();
+            // PLS has nowhere to keep the album
+            let expected = match format {
+                PlaylistFormat::Pls => Entry { album: None, ..file.entries[0].clone() },
+                _ => file.entries[0].clone(),
+            };
+            assert_eq!(expected, parsed.entries[0], "{format:?}");
+            assert_eq!(file.entries[1].location, parsed.entries[1].location, "{format:?}");
+        }
+        assert_eq!(file.name, parse(PlaylistFormat::Xspf, &write(PlaylistFormat::Xspf, &file)).unwrap().name);
+
+        // Numbers out of order or huge still come out in order, without room for the gaps
+        let parsed = parse(PlaylistFormat::Pls, "[playlist]\nFile999999999999=b.mp3\nFile2=a.mp3\nTitle2=A - a\n").unwrap();
+        assert_eq!(vec!["a.mp3", "b.mp3"], parsed.entries.iter().map(|e| e.location.as_str()).collect::<Vec<_>>());
+
+        // Tracks with and without attributes are read in the order they come
+        let parsed = parse(PlaylistFormat::Xspf, "<playlist><trackList>\
+            <track xml:base=\"music/\"><location>a.mp3</location></track>\
+            <track><location>b.mp3</location></track>\
+            <track\n  xml:base=\"music/\"><location>c.mp3</location></track>\
+        </trackList></playlist>").unwrap();
+        assert_eq!(vec!["a.mp3", "b.mp3", "c.mp3"], parsed.entries.iter().map(|e| e.location.as_str()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn resolve_entries() {
+        let songs = vec![
+            song("1", "So What", "Miles Davis", 562),
+            song("2", "Blue in Green", "Miles Davis", 337),
+            song("3", "Blue in Green", "Bill Evans", 320),
+        ];
+        let file = parse(PlaylistFormat::M3u, "\
+#EXTM3U
+#EXTINF:335,miles davis - Blue In Green
+somewhere/else.mp3
+music/01 - So What.flac
+#EXTINF:100,Nobody - Nothing
+missing.mp3
+").unwrap();
+
+        let (resolved, unresolved) = resolve(&file, &songs);
+        assert_eq!(vec![songs[1].0.clone(), songs[0].0.clone()], resolved);
+        assert_eq!(1, unresolved.len());
+        assert_eq!("missing.mp3", unresolved[0].location);
+    }
+}
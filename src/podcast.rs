@@ -0,0 +1,223 @@
+use std::{collections::HashMap, fs, io, path::PathBuf, time::Duration};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{subsonic, Item, SlibError};
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct PodcastChannel {
+    pub item: Item,
+    /// The feed url
+    pub url: String,
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct PodcastEpisode {
+    /// Keyed by the episode id, which stays the same once the server has the episode
+    pub item: Item,
+    /// The id to stream or download, once the server has fetched the episode
+    #[serde(default)]
+    pub stream_id: Option<String>,
+    pub channel_id: String,
+    pub description: Option<String>,
+    /// When it was published, as the server reports it
+    pub published: Option<String>,
+    pub length: Option<Duration>,
+    /// Whether the server has fetched the episode, as opposed to only knowing of it
+    pub available: bool,
+    pub played: bool,
+    /// Where to resume from
+    pub position: Duration,
+}
+
+impl PodcastChannel {
+    /// Read a channel of `getPodcasts`
+    pub fn from_subsonic(value: &Value) -> Option<PodcastChannel>
+    {
+        Some(PodcastChannel {
+            item: Item {
+                name: value["title"].as_str().unwrap_or_default().to_string(),
+                id: value["id"].as_str()?.to_string(),
+                image_path: value["coverArt"].as_str().unwrap_or_default().to_string(),
+            },
+            url: value["url"].as_str()?.to_string(),
+            description: value["description"].as_str().map(String::from),
+        })
+    }
+}
+
+impl PodcastEpisode {
+    /// Read an episode of `getPodcasts`, episodes only get a stream id once the server has them
+    pub fn from_subsonic(value: &Value) -> Option<PodcastEpisode>
+    {
+        let available = value["status"] == "completed";
+        Some(PodcastEpisode {
+            item: Item {
+                name: value["title"].as_str().unwrap_or_default().to_string(),
+                id: value["id"].as_str()?.to_string(),
+                image_path: value["coverArt"].as_str().unwrap_or_default().to_string(),
+            },
+            stream_id: value["streamId"].as_str().filter(|_| available).map(String::from),
+            channel_id: value["channelId"].as_str().unwrap_or_default().to_string(),
+            description: value["description"].as_str().map(String::from),
+            published: value["publishDate"].as_str().map(String::from),
+            length: value["duration"].as_u64().map(Duration::from_secs),
+            available,
+            played: false,
+            position: Duration::ZERO,
+        })
+    }
+}
+
+/// Return every channel and its episodes
+pub fn fetch(server: &subsonic::Server) -> Result<Vec<(PodcastChannel, Vec<PodcastEpisode>)>, SlibError>
+{
+    let response = server.call("getPodcasts", &[("includeEpisodes", "true")])?;
+    let channels = response["podcasts"]["channel"].as_array().cloned().unwrap_or_default();
+    Ok(channels.iter().filter_map(|channel| {
+        let episodes = channel["episode"].as_array()
+            .map(|e| e.iter().filter_map(PodcastEpisode::from_subsonic).collect())
+            .unwrap_or_default();
+        Some((PodcastChannel::from_subsonic(channel)?, episodes))
+    }).collect())
+}
+
+pub fn subscribe(server: &subsonic::Server, url: &str) -> Result<(), SlibError>
+{
+    server.call("createPodcastChannel", &[("url", url)]).map(|_| ())
+}
+
+pub fn unsubscribe(server: &subsonic::Server, channel_id: &str) -> Result<(), SlibError>
+{
+    server.call("deletePodcastChannel", &[("id", channel_id)]).map(|_| ())
+}
+
+/// Have the server fetch an episode from the feed, so it can be streamed or downloaded
+pub fn fetch_episode(server: &subsonic::Server, episode_id: &str) -> Result<(), SlibError>
+{
+    server.call("downloadPodcastEpisode", &[("id", episode_id)]).map(|_| ())
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Default)]
+struct EpisodeState {
+    played: bool,
+    position: Duration,
+    /// As of the last `apply`
+    #[serde(default)]
+    stream_id: Option<String>,
+}
+
+/// Played flags and resume positions, which Subsonic doesn't keep, saved across restarts.
+/// Also which ids are episodes, so they can be streamed by their stream id.
+pub struct PodcastProgress {
+    path: PathBuf,
+    episodes: HashMap<String, EpisodeState>,
+}
+
+impl PodcastProgress {
+    pub fn load(path: impl Into<PathBuf>) -> io::Result<PodcastProgress>
+    {
+        let path = path.into();
+        let episodes = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(PodcastProgress{path, episodes})
+    }
+
+    fn save(&self) -> io::Result<()>
+    {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string(&self.episodes)?)?;
+        fs::rename(tmp, &self.path)
+    }
+
+    pub fn set_position(&mut self, episode_id: &str, position: Duration) -> io::Result<()>
+    {
+        self.episodes.entry(episode_id.to_string()).or_default().position = position;
+        self.save()
+    }
+
+    /// Marking an episode played or unplayed starts it over
+    pub fn mark_played(&mut self, episode_id: &str, played: bool) -> io::Result<()>
+    {
+        let state = self.episodes.entry(episode_id.to_string()).or_default();
+        state.played = played;
+        state.position = Duration::ZERO;
+        self.save()
+    }
+
+    pub fn position(&self, episode_id: &str) -> Duration
+    {
+        self.episodes.get(episode_id).map_or(Duration::ZERO, |e| e.position)
+    }
+
+    /// Fill in the played flags and positions of episodes from the server, and remember their stream ids
+    pub fn apply(&mut self, episodes: &mut [PodcastEpisode]) -> io::Result<()>
+    {
+        for episode in episodes {
+            let state = self.episodes.entry(episode.item.id.clone()).or_default();
+            state.stream_id = episode.stream_id.clone();
+            episode.played = state.played;
+            episode.position = state.position;
+        }
+        self.save()
+    }
+
+    /// The id to stream or download an item by: the stream id of an episode, or the id itself
+    /// for anything that isn't one. None for an episode the server hasn't fetched yet.
+    pub fn media_id<'a>(&'a self, id: &'a str) -> Option<&'a str>
+    {
+        match self.episodes.get(id) {
+            Some(state) => state.stream_id.as_deref(),
+            None => Some(id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress() {
+        let path = std::env::temp_dir().join(format!("slib-podcasts-{}.json", std::process::id()));
+        let episode: Value = serde_json::from_str(r#"{
+            "id": "ep-1", "streamId": "song-9", "channelId": "ch-1", "title": "Episode One",
+            "status": "completed", "duration": 3600
+        }"#).unwrap();
+        let mut episodes = vec![PodcastEpisode::from_subsonic(&episode).unwrap()];
+        assert_eq!("ep-1", episodes[0].item.id);
+        assert_eq!(Some("song-9"), episodes[0].stream_id.as_deref());
+        assert_eq!(Some(Duration::from_secs(3600)), episodes[0].length);
+
+        // Progress from before the server had the episode still applies after
+        let mut progress = PodcastProgress::load(&path).unwrap();
+        let mut pending = episode.clone();
+        pending["status"] = "downloading".into();
+        let pending = PodcastEpisode::from_subsonic(&pending).unwrap();
+        assert_eq!(None, pending.stream_id);
+        progress.set_position(&pending.item.id, Duration::from_secs(754)).unwrap();
+
+        // Can't be streamed until the server has it
+        assert_eq!(None, progress.media_id("ep-1"));
+        assert_eq!(Some("song-1"), progress.media_id("song-1"));
+
+        // Survives a restart
+        let mut progress = PodcastProgress::load(&path).unwrap();
+        progress.apply(&mut episodes).unwrap();
+        assert_eq!(Duration::from_secs(754), episodes[0].position);
+        assert!(!episodes[0].played);
+        assert_eq!(Some("song-9"), progress.media_id("ep-1"));
+
+        progress.mark_played("ep-1", true).unwrap();
+        progress.apply(&mut episodes).unwrap();
+        assert!(episodes[0].played);
+        assert_eq!(Duration::ZERO, episodes[0].position);
+        assert_eq!(Some("song-9"), PodcastProgress::load(&path).unwrap().media_id("ep-1"));
+
+        fs::remove_file(path).unwrap();
+    }
+}
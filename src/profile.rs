@@ -0,0 +1,219 @@
+use std::{fs, io, path::{Path, PathBuf}};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::{self, Transcoding}, subsonic};
+
+/// A Subsonic server to use, with everything kept for it apart from the other servers
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub name: String,
+    pub url: String,
+    pub user: String,
+    /// What the password is kept under in the credential store, never the password itself
+    pub credential: String,
+    /// Where the profile's covers, lyrics, playlists and other state live,
+    /// None for a dir named after the profile in the config's cache dir
+    #[serde(default)]
+    pub cache_dir: Option<PathBuf>,
+    /// Stream quality for this server, over the config's
+    #[serde(default)]
+    pub transcoding: Transcoding,
+}
+
+impl Profile {
+    pub fn server(&self, password: &str) -> subsonic::Server
+    {
+        subsonic::Server::new(&self.url, &self.user, password)
+    }
+
+    /// Fill in the cache dir from the config's, unless the profile has its own
+    pub fn resolve(&mut self, cache_dir: &Path)
+    {
+        self.cache_dir.get_or_insert_with(|| cache_dir.join(&self.name));
+    }
+
+    /// The cache dir, a profile that was never resolved falls back to the default cache dir
+    pub fn cache_dir(&self) -> PathBuf
+    {
+        self.cache_dir.clone().unwrap_or_else(|| config::default_cache_dir().join(&self.name))
+    }
+
+    pub fn playlists_dir(&self) -> PathBuf
+    {
+        self.cache_dir().join("playlists")
+    }
+
+    pub fn covers_dir(&self) -> PathBuf
+    {
+        self.cache_dir().join("covers")
+    }
+
+    pub fn lyrics_dir(&self) -> PathBuf
+    {
+        self.cache_dir().join("lyrics")
+    }
+
+    pub fn downloads_dir(&self) -> PathBuf
+    {
+        self.cache_dir().join("downloads")
+    }
+
+    pub fn index_path(&self) -> PathBuf
+    {
+        self.cache_dir().join("index.json")
+    }
+
+    pub fn bookmarks_path(&self) -> PathBuf
+    {
+        self.cache_dir().join("bookmarks.json")
+    }
+
+    pub fn podcasts_path(&self) -> PathBuf
+    {
+        self.cache_dir().join("podcasts.json")
+    }
+
+    pub fn scrobbles_path(&self) -> PathBuf
+    {
+        self.cache_dir().join("scrobbles.json")
+    }
+
+    pub fn state_path(&self) -> PathBuf
+    {
+        self.cache_dir().join("state.json")
+    }
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct Profiles {
+    profiles: Vec<Profile>,
+    active: Option<String>,
+}
+
+/// The known profiles and which one is in use, kept on disk
+pub struct ProfileStore {
+    path: PathBuf,
+    state: Profiles,
+}
+
+impl ProfileStore {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<ProfileStore>
+    {
+        let path = path.into();
+        let state = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Profiles::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(ProfileStore{path, state})
+    }
+
+    fn save(&self) -> io::Result<()>
+    {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string(&self.state)?)?;
+        fs::rename(tmp, &self.path)
+    }
+
+    pub fn list(&self) -> &[Profile]
+    {
+        &self.state.profiles
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Profile>
+    {
+        self.state.profiles.iter().find(|p| p.name == name)
+    }
+
+    pub fn active(&self) -> Option<&Profile>
+    {
+        self.get(self.state.active.as_deref()?)
+    }
+
+    /// Returns false if the name or the cache dir is already used, the first profile becomes active
+    pub fn add(&mut self, profile: Profile) -> io::Result<bool>
+    {
+        if self.state.profiles.iter().any(|p| p.name == profile.name || p.cache_dir() == profile.cache_dir()) {
+            return Ok(false);
+        }
+        fs::create_dir_all(profile.cache_dir())?;
+        if self.state.active.is_none() {
+            self.state.active = Some(profile.name.clone());
+        }
+        self.state.profiles.push(profile);
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Returns the removed profile so its cache can be cleaned up.
+    /// The active profile can't be removed, switch away from it first.
+    pub fn remove(&mut self, name: &str) -> io::Result<Option<Profile>>
+    {
+        if self.state.active.as_deref() == Some(name) {
+            return Ok(None);
+        }
+        let Some(index) = self.state.profiles.iter().position(|p| p.name == name) else { return Ok(None) };
+        let profile = self.state.profiles.remove(index);
+        self.save()?;
+        Ok(Some(profile))
+    }
+
+    /// Returns false if there is no such profile
+    pub fn set_transcoding(&mut self, name: &str, transcoding: Transcoding) -> io::Result<bool>
+    {
+        let Some(profile) = self.state.profiles.iter_mut().find(|p| p.name == name) else { return Ok(false) };
+        profile.transcoding = transcoding;
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Returns the profile switched to, None if there is no such profile
+    pub fn switch(&mut self, name: &str) -> io::Result<Option<&Profile>>
+    {
+        if self.get(name).is_none() {
+            return Ok(None);
+        }
+        self.state.active = Some(name.to_string());
+        self.save()?;
+        Ok(self.active())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles() {
+        let dir = std::env::temp_dir().join(format!("slib-profiles-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let profile = |name: &str| Profile {
+            name: name.to_string(),
+            url: format!("https://{name}.example"),
+            user: "user".to_string(),
+            credential: format!("slib/{name}"),
+            cache_dir: Some(dir.join(name)),
+            transcoding: Transcoding::default(),
+        };
+
+        let mut store = ProfileStore::open(dir.join("profiles.json")).unwrap();
+        assert!(store.add(profile("home")).unwrap());
+        assert!(store.add(profile("work")).unwrap());
+        assert!(!store.add(profile("home")).unwrap());
+        assert!(!store.add(Profile { name: "other".to_string(), ..profile("work") }).unwrap());
+        assert_eq!("home", store.active().unwrap().name);
+
+        assert!(store.remove("home").unwrap().is_none());
+        assert_eq!(dir.join("work/playlists"), store.switch("work").unwrap().unwrap().playlists_dir());
+        assert!(store.switch("missing").unwrap().is_none());
+
+        // Survives a restart
+        let mut store = ProfileStore::open(dir.join("profiles.json")).unwrap();
+        assert_eq!("work", store.active().unwrap().name);
+        assert_eq!(Some(profile("home")), store.remove("home").unwrap());
+        assert_eq!(1, store.list().len());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
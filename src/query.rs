@@ -0,0 +1,305 @@
+use std::cmp::Ordering;
+use serde::{Deserialize, Serialize};
+
+use crate::{smart::{random_seed, shuffle_seeded}, Item};
+
+/// What a library fetch can be sorted by
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SortField {
+    Name,
+    Artist,
+    Year,
+    /// When it was added to the server
+    Added,
+    PlayCount,
+    LastPlayed,
+    Random,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum SortDirection {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+/// Narrow a library fetch down, unset fields don't filter
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct LibraryFilter {
+    pub genre: Option<String>,
+    /// Inclusive range of years
+    pub years: Option<(u16, u16)>,
+    pub artist: Option<String>,
+    /// Only favorites
+    pub starred: bool,
+    /// Only what is available offline
+    pub downloaded: bool,
+}
+
+/// How to sort, filter and page a library fetch, anything left out takes its default
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct LibraryQuery {
+    pub sort: Option<(SortField, SortDirection)>,
+    pub filter: LibraryFilter,
+    pub offset: usize,
+    /// No limit means everything after the offset
+    pub limit: Option<usize>,
+    /// What a random sort is shuffled by, so every page of it comes from the same order.
+    /// None shuffles differently on every fetch.
+    pub seed: Option<u64>,
+}
+
+/// What the daemon knows about an entry in its library, for running queries against
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct LibraryEntry {
+    pub item: Item,
+    pub artist: String,
+    pub genre: Option<String>,
+    pub year: Option<u16>,
+    pub starred: bool,
+    pub downloaded: bool,
+    /// Seconds since the epoch
+    pub added: u64,
+    pub play_count: u32,
+    /// Seconds since the epoch
+    pub last_played: Option<u64>,
+}
+
+impl LibraryQuery {
+    fn sorted(field: SortField, direction: SortDirection) -> LibraryQuery {
+        LibraryQuery { sort: Some((field, direction)), ..Default::default() }
+    }
+
+    /// Recently added, like the Subsonic `newest` list
+    pub fn newest() -> LibraryQuery {
+        Self::sorted(SortField::Added, SortDirection::Descending)
+    }
+
+    /// Most played, like the Subsonic `frequent` list
+    pub fn frequent() -> LibraryQuery {
+        Self::sorted(SortField::PlayCount, SortDirection::Descending)
+    }
+
+    /// Recently played, like the Subsonic `recent` list
+    pub fn recent() -> LibraryQuery {
+        Self::sorted(SortField::LastPlayed, SortDirection::Descending)
+    }
+
+    /// Shuffled with a fresh seed, reuse the query to page through the same shuffle
+    pub fn random() -> LibraryQuery {
+        LibraryQuery { seed: Some(random_seed()), ..Self::sorted(SortField::Random, SortDirection::Ascending) }
+    }
+
+    /// Pick a seed for a random sort that doesn't have one, before fetching it in pieces
+    pub fn seeded(mut self) -> LibraryQuery {
+        if matches!(self.sort, Some((SortField::Random, _))) {
+            self.seed.get_or_insert_with(random_seed);
+        }
+        self
+    }
+
+    pub fn alphabetical_by_name() -> LibraryQuery {
+        Self::sorted(SortField::Name, SortDirection::Ascending)
+    }
+
+    pub fn alphabetical_by_artist() -> LibraryQuery {
+        Self::sorted(SortField::Artist, SortDirection::Ascending)
+    }
+
+    /// Albums from a range of years, in reverse if `from` is after `to` like Subsonic
+    pub fn by_year(from: u16, to: u16) -> LibraryQuery {
+        let direction = if from > to { SortDirection::Descending } else { SortDirection::Ascending };
+        let mut query = Self::sorted(SortField::Year, direction);
+        query.filter.years = Some((from.min(to), from.max(to)));
+        query
+    }
+
+    pub fn by_genre(genre: impl Into<String>) -> LibraryQuery {
+        let mut query = Self::alphabetical_by_name();
+        query.filter.genre = Some(genre.into());
+        query
+    }
+
+    /// The Subsonic `getAlbumList2` type that matches this query, for daemons that let the server sort.
+    /// None if the server has no list in that order, like names in reverse, or the query filters in
+    /// a way the list can't, like by artist or by more than one of genre, years and starred.
+    /// The daemon has to filter and sort itself then.
+    pub fn album_list_type(&self) -> Option<&'static str> {
+        use SortDirection::*;
+        let filter = &self.filter;
+        if filter.artist.is_some() || filter.downloaded {
+            return None;
+        }
+        // Each list takes at most one of these
+        if [filter.years.is_some(), filter.genre.is_some(), filter.starred].into_iter().filter(|f| *f).count() > 1 {
+            return None;
+        }
+        if self.filter.years.is_some() {
+            return matches!(self.sort, None | Some((SortField::Year, _))).then_some("byYear");
+        }
+        let by_name = matches!(self.sort, None | Some((SortField::Name, Ascending)));
+        if self.filter.genre.is_some() {
+            return by_name.then_some("byGenre");
+        }
+        if self.filter.starred {
+            return by_name.then_some("starred");
+        }
+        match self.sort {
+            Some((SortField::Added, Descending)) => Some("newest"),
+            Some((SortField::PlayCount, Descending)) => Some("frequent"),
+            Some((SortField::LastPlayed, Descending)) => Some("recent"),
+            Some((SortField::Random, _)) => Some("random"),
+            Some((SortField::Artist, Ascending)) => Some("alphabeticalByArtist"),
+            Some((SortField::Year, _)) => Some("byYear"),
+            Some((SortField::Name, Ascending)) | None => Some("alphabeticalByName"),
+            // The server only has these the other way around
+            _ => None,
+        }
+    }
+
+    /// `fromYear` and `toYear` for a `byYear` list, the server goes newest first when `fromYear` is the later one
+    pub fn year_range(&self) -> (u16, u16) {
+        let (from, to) = self.filter.years.unwrap_or((0, 9999));
+        match self.sort {
+            Some((_, SortDirection::Descending)) => (to, from),
+            _ => (from, to),
+        }
+    }
+
+    /// Whether an entry passes the filter
+    pub fn matches(&self, entry: &LibraryEntry) -> bool {
+        let filter = &self.filter;
+        filter.genre.as_ref().is_none_or(|g| entry.genre.as_ref().is_some_and(|e| e.eq_ignore_ascii_case(g)))
+            && filter.years.is_none_or(|(from, to)| entry.year.is_some_and(|y| from <= y && y <= to))
+            && filter.artist.as_ref().is_none_or(|a| entry.artist.eq_ignore_ascii_case(a))
+            && (!filter.starred || entry.starred)
+            && (!filter.downloaded || entry.downloaded)
+    }
+
+    /// Filter, sort and page a library
+    pub fn apply(&self, entries: &[LibraryEntry]) -> Vec<Item> {
+        let mut entries: Vec<&LibraryEntry> = entries.iter().filter(|e| self.matches(e)).collect();
+
+        if let Some((field, direction)) = self.sort {
+            if field == SortField::Random {
+                shuffle_seeded(&mut entries, self.seed.unwrap_or_else(random_seed));
+            }
+            else {
+                // Stable, so ties keep the order the daemon gave
+                entries.sort_by(|a, b| {
+                    let ordering = compare(field, a, b);
+                    match direction {
+                        SortDirection::Ascending => ordering,
+                        SortDirection::Descending => ordering.reverse(),
+                    }
+                });
+            }
+        }
+
+        entries.into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .map(|e| e.item.clone())
+            .collect()
+    }
+}
+
+fn compare(field: SortField, a: &LibraryEntry, b: &LibraryEntry) -> Ordering {
+    match field {
+        SortField::Name => a.item.name.to_lowercase().cmp(&b.item.name.to_lowercase()),
+        SortField::Artist => a.artist.to_lowercase().cmp(&b.artist.to_lowercase()),
+        SortField::Year => a.year.cmp(&b.year),
+        SortField::Added => a.added.cmp(&b.added),
+        SortField::PlayCount => a.play_count.cmp(&b.play_count),
+        SortField::LastPlayed => a.last_played.cmp(&b.last_played),
+        SortField::Random => Ordering::Equal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, genre: &str, year: u16, play_count: u32, starred: bool) -> LibraryEntry {
+        LibraryEntry {
+            item: Item { name: name.to_string(), id: name.to_string(), image_path: String::new() },
+            artist: "Artist".to_string(),
+            genre: Some(genre.to_string()),
+            year: Some(year),
+            starred,
+            downloaded: false,
+            added: year as u64,
+            play_count,
+            last_played: None,
+        }
+    }
+
+    fn names(items: Vec<Item>) -> Vec<String> {
+        items.into_iter().map(|i| i.name).collect()
+    }
+
+    #[test]
+    fn apply_queries() {
+        let library = vec![
+            entry("b", "Jazz", 1959, 10, true),
+            entry("A", "Rock", 1971, 3, false),
+            entry("c", "Jazz", 1964, 7, false),
+            entry("d", "Jazz", 1980, 1, true),
+        ];
+
+        assert_eq!(vec!["A", "b", "c", "d"], names(LibraryQuery::alphabetical_by_name().apply(&library)));
+        assert_eq!(vec!["d", "A"], names(LibraryQuery { limit: Some(2), ..LibraryQuery::newest() }.apply(&library)));
+        assert_eq!(vec!["c", "b"], names(LibraryQuery::by_year(1970, 1950).apply(&library)));
+        assert_eq!(vec!["c", "d"], names(LibraryQuery { offset: 1, ..LibraryQuery::by_genre("jazz") }.apply(&library)));
+
+        let mut query = LibraryQuery::frequent();
+        query.filter.starred = true;
+        assert_eq!(vec!["b", "d"], names(query.apply(&library)));
+        // The server's starred list is by name, so it can't stand in for this one
+        assert_eq!(None, query.album_list_type());
+        query.sort = None;
+        assert_eq!(Some("starred"), query.album_list_type());
+        assert_eq!(Some("frequent"), LibraryQuery::frequent().album_list_type());
+        assert_eq!(None, LibraryQuery::sorted(SortField::Name, SortDirection::Descending).album_list_type());
+        let by_year = LibraryQuery::by_year(1970, 1950);
+        assert_eq!((Some("byYear"), (1970, 1950)), (by_year.album_list_type(), by_year.year_range()));
+
+        // Filters the server lists can't apply aren't dropped, the daemon does it all
+        let mut query = LibraryQuery::by_year(1950, 1970);
+        query.filter.genre = Some("Jazz".to_string());
+        assert_eq!(None, query.album_list_type());
+        assert_eq!(vec!["b", "c"], names(query.apply(&library)));
+        let mut query = LibraryQuery::newest();
+        query.filter.artist = Some("Artist".to_string());
+        assert_eq!(None, query.album_list_type());
+        query.filter.artist = None;
+        query.filter.downloaded = true;
+        assert_eq!(None, query.album_list_type());
+    }
+
+    #[test]
+    fn partial_queries() {
+        let query: LibraryQuery = serde_json::from_str(r#"{"limit":5,"filter":{"genre":"Jazz"}}"#).unwrap();
+        assert_eq!(Some(5), query.limit);
+        assert_eq!(Some("Jazz".to_string()), query.filter.genre);
+        assert_eq!(LibraryQuery::default(), serde_json::from_str("{}").unwrap());
+    }
+
+    #[test]
+    fn random_pages() {
+        let library: Vec<LibraryEntry> = (0..20).map(|i| entry(&i.to_string(), "Jazz", 1960, 0, false)).collect();
+        let query = LibraryQuery::random();
+        let all = query.apply(&library);
+
+        // Paging through the same shuffle neither repeats nor skips
+        let pages: Vec<Item> = (0..4)
+            .flat_map(|page| LibraryQuery { offset: page * 5, limit: Some(5), ..query.clone() }.apply(&library))
+            .collect();
+        assert_eq!(all, pages);
+        let mut sorted = names(all);
+        sorted.sort_by_key(|n| n.parse::<u32>().unwrap());
+        assert_eq!((0..20).map(|i| i.to_string()).collect::<Vec<_>>(), sorted);
+    }
+}
@@ -0,0 +1,189 @@
+use std::io::{self, Read};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{subsonic, Item, SlibError};
+
+/// An internet radio station known to the Subsonic server
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct RadioStation {
+    pub item: Item,
+    pub stream_url: String,
+    pub homepage_url: Option<String>,
+}
+
+impl RadioStation {
+    /// Read an entry of `getInternetRadioStations`
+    pub fn from_subsonic(value: &Value) -> Option<RadioStation>
+    {
+        Some(RadioStation {
+            item: Item {
+                name: value["name"].as_str()?.to_string(),
+                id: value["id"].as_str()?.to_string(),
+                image_path: value["coverArt"].as_str().unwrap_or_default().to_string(),
+            },
+            stream_url: value["streamUrl"].as_str()?.to_string(),
+            homepage_url: value["homePageUrl"].as_str().map(String::from),
+        })
+    }
+}
+
+pub fn fetch_stations(server: &subsonic::Server) -> Result<Vec<RadioStation>, SlibError>
+{
+    let response = server.call("getInternetRadioStations", &[])?;
+    let stations = response["internetRadioStations"]["internetRadioStation"].as_array().cloned().unwrap_or_default();
+    Ok(stations.iter().filter_map(RadioStation::from_subsonic).collect())
+}
+
+pub fn create_station(server: &subsonic::Server, name: &str, stream_url: &str, homepage_url: Option<&str>) -> Result<(), SlibError>
+{
+    let mut params = vec![("name", name), ("streamUrl", stream_url)];
+    if let Some(homepage_url) = homepage_url {
+        params.push(("homepageUrl", homepage_url));
+    }
+    server.call("createInternetRadioStation", &params).map(|_| ())
+}
+
+pub fn delete_station(server: &subsonic::Server, id: &str) -> Result<(), SlibError>
+{
+    server.call("deleteInternetRadioStation", &[("id", id)]).map(|_| ())
+}
+
+/// What a live stream says about itself, in place of a song length
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct StreamMetadata {
+    /// The ICY `StreamTitle`, usually the song that is on
+    pub title: Option<String>,
+    /// `icy-name`
+    pub name: Option<String>,
+    /// `icy-genre`
+    pub genre: Option<String>,
+    /// `icy-br` in kbps
+    pub bitrate: Option<u32>,
+}
+
+impl StreamMetadata {
+    /// Fill in from the response headers, returns the `icy-metaint` if the stream has inline titles
+    pub fn from_headers<'a>(headers: impl IntoIterator<Item = (&'a str, &'a str)>) -> (StreamMetadata, Option<usize>)
+    {
+        let mut metadata = StreamMetadata::default();
+        let mut metaint = None;
+        for (key, value) in headers {
+            match key.to_ascii_lowercase().as_str() {
+                "icy-name" => metadata.name = Some(value.trim().to_string()),
+                "icy-genre" => metadata.genre = Some(value.trim().to_string()),
+                "icy-br" => metadata.bitrate = value.trim().parse().ok(),
+                "icy-metaint" => metaint = value.trim().parse().ok(),
+                _ => {},
+            }
+        }
+        (metadata, metaint)
+    }
+}
+
+/// Pull `StreamTitle` out of an ICY metadata block like `StreamTitle='Artist - Song';`
+pub fn parse_stream_title(block: &str) -> Option<String>
+{
+    let start = block.find("StreamTitle='")? + "StreamTitle='".len();
+    let end = block[start..].find("';").map_or(block.len(), |e| start + e);
+    let title = block[start..end].trim();
+    (!title.is_empty()).then(|| title.to_string())
+}
+
+/// Strips the ICY metadata out of a stream, leaving just the audio, and keeps the latest title.
+/// Request the stream with `Icy-MetaData: 1` and pass the `icy-metaint` header here.
+pub struct IcyReader<R> {
+    inner: R,
+    metaint: usize,
+    until_metadata: usize,
+    pub metadata: StreamMetadata,
+}
+
+impl<R: Read> IcyReader<R> {
+    pub fn new(inner: R, metaint: usize, metadata: StreamMetadata) -> IcyReader<R>
+    {
+        IcyReader { inner, metaint, until_metadata: metaint, metadata }
+    }
+
+    /// Reads the next metadata block, false if the stream ended right before it
+    fn read_metadata(&mut self) -> io::Result<bool>
+    {
+        let mut len = [0];
+        loop {
+            match self.inner.read(&mut len) {
+                Ok(0) => return Ok(false),
+                Ok(_) => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        let mut block = vec![0; len[0] as usize * 16];
+        self.inner.read_exact(&mut block)?;
+
+        // An empty block means the title hasn't changed
+        if !block.is_empty() {
+            let block = String::from_utf8_lossy(&block);
+            if let Some(title) = parse_stream_title(block.trim_end_matches('\0')) {
+                self.metadata.title = Some(title);
+            }
+        }
+        self.until_metadata = self.metaint;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for IcyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.metaint == 0 {
+            return self.inner.read(buf);
+        }
+        if self.until_metadata == 0 && !self.read_metadata()? {
+            return Ok(0);
+        }
+        let len = buf.len().min(self.until_metadata);
+        let read = self.inner.read(&mut buf[..len])?;
+        self.until_metadata -= read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn icy_metadata() {
+        let (metadata, metaint) = StreamMetadata::from_headers([("icy-name", "Jazz FM"), ("icy-br", "128"), ("icy-metaint", "4")]);
+        assert_eq!(Some(4), metaint);
+        assert_eq!(Some(128), metadata.bitrate);
+
+        let mut block = b"StreamTitle='Miles Davis - So What';".to_vec();
+        block.resize(48, 0);
+        let mut stream = b"abcd".to_vec();
+        stream.push(3);
+        stream.extend(block);
+        stream.extend(b"efgh");
+        stream.push(0);
+        stream.extend(b"ij");
+
+        let mut reader = IcyReader::new(&stream[..], 4, metadata);
+        let mut audio = Vec::new();
+        reader.read_to_end(&mut audio).unwrap();
+        assert_eq!(b"abcdefghij".to_vec(), audio);
+        assert_eq!(Some("Miles Davis - So What".to_string()), reader.metadata.title);
+        assert_eq!(Some("Jazz FM".to_string()), reader.metadata.name);
+    }
+
+    #[test]
+    fn icy_end_on_boundary() {
+        let mut stream = b"abcd".to_vec();
+        stream.push(0);
+        stream.extend(b"efgh");
+
+        let mut reader = IcyReader::new(&stream[..], 4, StreamMetadata::default());
+        let mut audio = Vec::new();
+        reader.read_to_end(&mut audio).unwrap();
+        assert_eq!(b"abcdefgh".to_vec(), audio);
+        assert_eq!(0, reader.read(&mut [0; 8]).unwrap());
+    }
+}
@@ -0,0 +1,173 @@
+use std::{fs, path::Path};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::lyrics::{decode, flac_comments, id3_frames, skip_terminated};
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplayGainMode {
+    #[default]
+    Off,
+    /// Every song at the same loudness
+    Track,
+    /// Every album at the same loudness, keeping the songs of an album as they are to each other
+    Album,
+}
+
+/// How to even out loudness between songs
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplayGain {
+    pub mode: ReplayGainMode,
+    /// dB added on top of the gain, since ReplayGain aims quieter than most masters
+    pub preamp: f32,
+    /// Hold the gain down so the peak doesn't go over full scale
+    pub prevent_clipping: bool,
+    /// dB for songs that have no gain at all
+    pub fallback: f32,
+}
+
+impl Default for ReplayGain {
+    fn default() -> ReplayGain {
+        ReplayGain { mode: ReplayGainMode::Off, preamp: 0.0, prevent_clipping: true, fallback: 0.0 }
+    }
+}
+
+/// The ReplayGain of a song, gains in dB and peaks as a fraction of full scale
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy, Default)]
+pub struct GainInfo {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl GainInfo {
+    /// Read the OpenSubsonic `replayGain` of a song
+    pub fn from_subsonic(song: &Value) -> GainInfo
+    {
+        let gain = &song["replayGain"];
+        let field = |key: &str| gain[key].as_f64().map(|v| v as f32);
+        GainInfo {
+            track_gain: field("trackGain"),
+            track_peak: field("trackPeak"),
+            album_gain: field("albumGain"),
+            album_peak: field("albumPeak"),
+        }
+    }
+
+    /// Read `REPLAYGAIN_*` tags, with values like `-6.20 dB`
+    pub fn from_tags<K: AsRef<str>, V: AsRef<str>>(tags: impl IntoIterator<Item = (K, V)>) -> GainInfo
+    {
+        let mut info = GainInfo::default();
+        for (key, value) in tags {
+            let value = value.as_ref().trim().trim_end_matches("dB").trim_end_matches("db").trim().parse().ok()
+                .filter(|v: &f32| v.is_finite());
+            match key.as_ref().to_ascii_uppercase().as_str() {
+                "REPLAYGAIN_TRACK_GAIN" => info.track_gain = value,
+                "REPLAYGAIN_TRACK_PEAK" => info.track_peak = value,
+                "REPLAYGAIN_ALBUM_GAIN" => info.album_gain = value,
+                "REPLAYGAIN_ALBUM_PEAK" => info.album_peak = value,
+                _ => {},
+            }
+        }
+        info
+    }
+
+    /// Read the tags of a downloaded song, ID3v2 `TXXX` frames or FLAC comments
+    pub fn read_tags(song: &Path) -> Option<GainInfo>
+    {
+        let data = fs::read(song).ok()?;
+        if data.starts_with(b"ID3") {
+            let tags = id3_frames(&data).into_iter()
+                .filter(|(id, body)| *id == b"TXXX" && !body.is_empty())
+                .filter_map(|(_, body)| {
+                    // Encoding, then a description naming the tag, then the value
+                    let encoding = body[0];
+                    let value = skip_terminated(encoding, &body[1..])?;
+                    let key = decode(encoding, &body[1..body.len() - value.len()])?;
+                    Some((key, decode(encoding, value)?))
+                });
+            Some(GainInfo::from_tags(tags))
+        }
+        else if data.starts_with(b"fLaC") {
+            Some(GainInfo::from_tags(flac_comments(&data)))
+        }
+        else {
+            None
+        }
+    }
+
+    /// Fill in whatever isn't known from somewhere else, like the server over the file tags
+    pub fn or(&self, other: &GainInfo) -> GainInfo
+    {
+        GainInfo {
+            track_gain: self.track_gain.or(other.track_gain),
+            track_peak: self.track_peak.or(other.track_peak),
+            album_gain: self.album_gain.or(other.album_gain),
+            album_peak: self.album_peak.or(other.album_peak),
+        }
+    }
+}
+
+impl ReplayGain {
+    /// Whether the preamp and fallback are numbers within 24 dB
+    pub fn is_valid(&self) -> bool
+    {
+        [self.preamp, self.fallback].iter().all(|db| db.is_finite() && db.abs() <= 24.0)
+    }
+
+    /// What to multiply a song's samples by, before the volume.
+    /// Falls back to the other kind of gain when the one for the mode is missing.
+    pub fn factor(&self, info: &GainInfo) -> f32
+    {
+        let (gain, peak) = match self.mode {
+            ReplayGainMode::Off => return 1.0,
+            ReplayGainMode::Track => (info.track_gain.or(info.album_gain), info.track_peak.or(info.album_peak)),
+            ReplayGainMode::Album => (info.album_gain.or(info.track_gain), info.album_peak.or(info.track_peak)),
+        };
+        let db = gain.map_or(self.fallback, |gain| gain + self.preamp);
+        let factor = 10f32.powf(db / 20.0);
+        match peak.filter(|&peak| self.prevent_clipping && peak > 0.0) {
+            Some(peak) => factor.min(1.0 / peak),
+            None => factor,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gain_factor() {
+        let info = GainInfo::from_tags([
+            ("REPLAYGAIN_TRACK_GAIN", "-6.02 dB"),
+            ("replaygain_track_peak", "0.5"),
+            ("REPLAYGAIN_ALBUM_GAIN", "+6.02 dB"),
+            ("REPLAYGAIN_ALBUM_PEAK", "0.8"),
+        ]);
+        let close = |a: f32, b: f32| (a - b).abs() < 0.01;
+
+        let mut replay_gain = ReplayGain { mode: ReplayGainMode::Track, ..ReplayGain::default() };
+        assert!(close(0.5, replay_gain.factor(&info)));
+        replay_gain.preamp = 6.02;
+        assert!(close(1.0, replay_gain.factor(&info)));
+
+        // Album gain would take the peak to 1.6, so it is held at full scale
+        replay_gain.mode = ReplayGainMode::Album;
+        assert!(close(1.25, replay_gain.factor(&info)));
+        replay_gain.prevent_clipping = false;
+        assert!(close(4.0, replay_gain.factor(&info)));
+
+        // Only track gain known, from the server
+        let server: Value = serde_json::from_str(r#"{"replayGain": {"trackGain": -12.04}}"#).unwrap();
+        let info = GainInfo::from_subsonic(&server).or(&GainInfo::default());
+        assert!(close(0.5, replay_gain.factor(&info)));
+        assert_eq!(1.0, ReplayGain::default().factor(&info));
+
+        assert!(!ReplayGain { fallback: f32::INFINITY, ..ReplayGain::default() }.is_valid());
+        assert_eq!(None, GainInfo::from_tags([("REPLAYGAIN_TRACK_GAIN", "NaN dB")]).track_gain);
+    }
+}
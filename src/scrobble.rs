@@ -0,0 +1,257 @@
+use std::{fs, io, path::PathBuf, time::{Duration, SystemTime, UNIX_EPOCH}};
+use serde::{Deserialize, Serialize};
+
+use crate::{subsonic, Item, SlibError};
+
+/// Songs shorter than this are never scrobbled
+const MIN_LENGTH: Duration = Duration::from_secs(30);
+/// A song is scrobbled after half of it or this much has played, whichever comes first
+const MAX_THRESHOLD: Duration = Duration::from_secs(4 * 60);
+
+/// A finished listen
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct Scrobble {
+    pub song: Item,
+    /// When the song started, in milliseconds since the epoch
+    pub time: u64,
+}
+
+/// Somewhere plays get reported to, like Subsonic or ListenBrainz
+pub trait ScrobbleSink {
+    /// Identifies the sink in the offline queue, so it should stay the same across restarts
+    fn name(&self) -> &str;
+    /// Report the song that just started
+    fn now_playing(&mut self, song: &Item) -> Result<(), SlibError>;
+    /// Report a listen
+    fn submit(&mut self, scrobble: &Scrobble) -> Result<(), SlibError>;
+}
+
+impl ScrobbleSink for subsonic::Server {
+    fn name(&self) -> &str {
+        "subsonic"
+    }
+
+    fn now_playing(&mut self, song: &Item) -> Result<(), SlibError> {
+        self.call("scrobble", &[("id", &song.id), ("submission", "false")]).map(|_| ())
+    }
+
+    fn submit(&mut self, scrobble: &Scrobble) -> Result<(), SlibError> {
+        let time = scrobble.time.to_string();
+        self.call("scrobble", &[("id", &scrobble.song.id), ("time", &time), ("submission", "true")]).map(|_| ())
+    }
+}
+
+/// How long a song has to play before it counts, or None if it never does
+pub fn threshold(length: Duration) -> Option<Duration>
+{
+    if length < MIN_LENGTH {
+        None
+    }
+    else {
+        Some((length / 2).min(MAX_THRESHOLD))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct Pending {
+    sink: String,
+    scrobble: Scrobble,
+}
+
+struct Playing {
+    scrobble: Scrobble,
+    threshold: Option<Duration>,
+}
+
+/// Tracks the playing song and reports it to every sink.
+///
+/// Scrobbles that a sink fails to take are kept in a queue on disk and
+/// retried the next time anything is submitted or on `retry`.
+pub struct Scrobbler {
+    sinks: Vec<Box<dyn ScrobbleSink + Send>>,
+    queue_path: PathBuf,
+    queue: Vec<Pending>,
+    playing: Option<Playing>,
+}
+
+impl Scrobbler {
+    /// Create a scrobbler, loading any scrobbles left in the queue
+    pub fn new(queue_path: impl Into<PathBuf>) -> io::Result<Scrobbler>
+    {
+        let queue_path = queue_path.into();
+        let queue = match fs::read_to_string(&queue_path) {
+            Ok(data) => serde_json::from_str(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Scrobbler { sinks: Vec::new(), queue_path, queue, playing: None })
+    }
+
+    pub fn add_sink(&mut self, sink: Box<dyn ScrobbleSink + Send>)
+    {
+        self.sinks.push(sink);
+    }
+
+    /// The number of scrobbles waiting to be retried
+    pub fn pending(&self) -> usize
+    {
+        self.queue.len()
+    }
+
+    /// Call when a song starts playing
+    pub fn start(&mut self, song: Item, length: Duration)
+    {
+        // Now playing is only a hint, so it isn't worth queueing
+        for sink in &mut self.sinks {
+            let _ = sink.now_playing(&song);
+        }
+
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        self.playing = Some(Playing {
+            scrobble: Scrobble { song, time },
+            threshold: threshold(length),
+        });
+    }
+
+    /// Call with how long the current song has actually been listened to
+    pub fn played(&mut self, played: Duration) -> io::Result<()>
+    {
+        let Some(playing) = &mut self.playing else { return Ok(()) };
+        if playing.threshold.is_none_or(|t| played < t) {
+            return Ok(());
+        }
+        // Only scrobble once per play
+        playing.threshold = None;
+
+        let scrobble = playing.scrobble.clone();
+        for sink in &self.sinks {
+            self.queue.push(Pending { sink: sink.name().to_string(), scrobble: scrobble.clone() });
+        }
+        self.retry()?;
+        Ok(())
+    }
+
+    /// Call when the current song stops, whether it finished or not
+    pub fn stop(&mut self)
+    {
+        self.playing = None;
+    }
+
+    /// Submit queued scrobbles in order, returns how many are left
+    pub fn retry(&mut self) -> io::Result<usize>
+    {
+        let mut failed: Vec<String> = Vec::new();
+        let mut remaining = Vec::new();
+
+        for pending in self.queue.drain(..) {
+            // Keep the order, so once a sink fails it gets nothing else this round
+            if failed.contains(&pending.sink) {
+                remaining.push(pending);
+                continue;
+            }
+            let Some(sink) = self.sinks.iter_mut().find(|s| s.name() == pending.sink) else {
+                remaining.push(pending);
+                continue;
+            };
+            if sink.submit(&pending.scrobble).is_err() {
+                failed.push(pending.sink.clone());
+                remaining.push(pending);
+            }
+        }
+
+        self.queue = remaining;
+        self.save()?;
+        Ok(self.queue.len())
+    }
+
+    fn save(&self) -> io::Result<()>
+    {
+        if self.queue.is_empty() {
+            return match fs::remove_file(&self.queue_path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let tmp = self.queue_path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string(&self.queue)?)?;
+        fs::rename(tmp, &self.queue_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::{BufRead, BufReader, Write}, net::TcpListener, sync::mpsc, thread};
+
+    /// A Subsonic server that answers every request with ok and reports the request lines
+    fn stub_server() -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                // Skip the headers
+                let mut header = String::new();
+                while reader.read_line(&mut header).unwrap() > 2 {
+                    header.clear();
+                }
+                let body = r#"{"subsonic-response":{"status":"ok","version":"1.16.1"}}"#;
+                write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len()).unwrap();
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        (url, rx)
+    }
+
+    fn song() -> Item {
+        Item { name: "So What".to_string(), id: "42".to_string(), image_path: String::new() }
+    }
+
+    #[test]
+    fn thresholds() {
+        assert_eq!(None, threshold(Duration::from_secs(20)));
+        assert_eq!(Some(Duration::from_secs(100)), threshold(Duration::from_secs(200)));
+        assert_eq!(Some(Duration::from_secs(240)), threshold(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn submit_and_queue() {
+        let queue = std::env::temp_dir().join(format!("slib-scrobbles-{}.json", std::process::id()));
+
+        // Nothing is listening on this port
+        let offline = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let mut scrobbler = Scrobbler::new(&queue).unwrap();
+        scrobbler.add_sink(Box::new(subsonic::Server::new(offline, "user", "pass")));
+        scrobbler.start(song(), Duration::from_secs(200));
+        scrobbler.played(Duration::from_secs(120)).unwrap();
+        assert_eq!(1, scrobbler.pending());
+
+        // The queue survives a restart and goes out once the server is back
+        let (url, requests) = stub_server();
+        let mut scrobbler = Scrobbler::new(&queue).unwrap();
+        scrobbler.add_sink(Box::new(subsonic::Server::new(url, "user", "pass")));
+        assert_eq!(1, scrobbler.pending());
+        assert_eq!(0, scrobbler.retry().unwrap());
+        let request = requests.recv().unwrap();
+        assert!(request.contains("/rest/scrobble?") && request.contains("submission=true"));
+
+        scrobbler.start(song(), Duration::from_secs(200));
+        assert!(requests.recv().unwrap().contains("submission=false"));
+        scrobbler.played(Duration::from_secs(50)).unwrap();
+        scrobbler.played(Duration::from_secs(100)).unwrap();
+        scrobbler.played(Duration::from_secs(150)).unwrap();
+        assert!(requests.recv().unwrap().contains("submission=true"));
+        assert!(requests.try_recv().is_err());
+        assert!(!queue.exists());
+    }
+}
@@ -0,0 +1,180 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{index::fold, Item, SongInfo};
+
+/// How many results to return per category, Subsonic defaults to 20 each
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(default)]
+pub struct SearchLimits {
+    pub songs: usize,
+    pub albums: usize,
+    pub artists: usize,
+    pub playlists: usize,
+}
+
+impl Default for SearchLimits {
+    fn default() -> SearchLimits {
+        SearchLimits { songs: 20, albums: 20, artists: 20, playlists: 20 }
+    }
+}
+
+/// A search with optional field filters, parsed from text like `artist:"Miles Davis" year:1959 blue`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct SearchQuery {
+    /// Free text that isn't tied to a field
+    pub text: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub title: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<u16>,
+    #[serde(default)]
+    pub limits: SearchLimits,
+    /// Only search the local index, for offline use and type-ahead
+    #[serde(default)]
+    pub local: bool,
+}
+
+/// Search results split up by category, like Subsonic `search3`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct SearchResults {
+    pub songs: Vec<Item>,
+    pub albums: Vec<Item>,
+    pub artists: Vec<Item>,
+    pub playlists: Vec<Item>,
+}
+
+impl SearchQuery {
+    pub fn parse(text: &str) -> SearchQuery
+    {
+        let mut query = SearchQuery::default();
+        let mut words = Vec::new();
+
+        for token in tokenize(text) {
+            let field = token.split_once(':').filter(|(_, value)| !value.is_empty());
+            match field.map(|(key, value)| (key.to_ascii_lowercase(), value.to_string())) {
+                Some((key, value)) if key == "artist" => query.artist = Some(value),
+                Some((key, value)) if key == "album" => query.album = Some(value),
+                Some((key, value)) if key == "title" => query.title = Some(value),
+                Some((key, value)) if key == "genre" => query.genre = Some(value),
+                Some((key, value)) if key == "year" && value.parse::<u16>().is_ok() => query.year = value.parse().ok(),
+                _ => words.push(token),
+            }
+        }
+        query.text = words.join(" ");
+        query
+    }
+
+    /// The text to send to `search3`, which doesn't know about fields
+    pub fn search3_text(&self) -> String
+    {
+        [Some(&self.text), self.artist.as_ref(), self.album.as_ref(), self.title.as_ref()]
+            .into_iter()
+            .flatten()
+            .filter(|t| !t.is_empty())
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The parameters of a `search3` call for this query
+    pub fn search3_params(&self) -> Vec<(&'static str, String)>
+    {
+        vec![
+            ("query", self.search3_text()),
+            ("songCount", self.limits.songs.to_string()),
+            ("albumCount", self.limits.albums.to_string()),
+            ("artistCount", self.limits.artists.to_string()),
+        ]
+    }
+
+    /// Whether a song passes the field filters, for narrowing down `search3` results.
+    /// Text is compared folded, so case and diacritics don't matter.
+    pub fn matches_song(&self, song: &Item, info: &SongInfo) -> bool
+    {
+        contains(&self.title, &song.name)
+            && contains(&self.artist, &info.artist)
+            && contains(&self.album, &info.album.name)
+            && self.genre.as_ref().is_none_or(|g| info.genre.as_ref().is_some_and(|i| fold(i) == fold(g)))
+            && self.year.is_none_or(|y| info.year == Some(y))
+    }
+
+    /// Whether a name matches the free text, for searching playlists which `search3` doesn't cover
+    pub fn matches_name(&self, name: &str) -> bool
+    {
+        let name = fold(name);
+        self.text.split_whitespace().all(|word| name.contains(&fold(word)))
+    }
+}
+
+fn contains(filter: &Option<String>, value: &str) -> bool
+{
+    filter.as_ref().is_none_or(|f| fold(value).contains(&fold(f)))
+}
+
+/// Split on whitespace, keeping double quoted runs together
+fn tokenize(text: &str) -> Vec<String>
+{
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut quoted = false;
+
+    for c in text.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            },
+            c => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fields() {
+        let query = SearchQuery::parse(r#"artist:"Miles Davis" year:1959 blue GENRE:jazz year:soon"#);
+        assert_eq!("blue year:soon", query.text);
+        assert_eq!(Some("Miles Davis".to_string()), query.artist);
+        assert_eq!(Some("jazz".to_string()), query.genre);
+        assert_eq!(Some(1959), query.year);
+        assert_eq!("blue year:soon Miles Davis", query.search3_text());
+        assert!(query.matches_name("Blue Year:Soon mix"));
+    }
+
+    #[test]
+    fn folded_fields() {
+        let song = Item { name: "Jóga".to_string(), id: "1".to_string(), image_path: String::new() };
+        let info = SongInfo {
+            length: std::time::Duration::from_secs(300),
+            album: Item { name: "Homogenic".to_string(), id: "al-1".to_string(), image_path: String::new() },
+            // Decomposed, the ö is an o and a combining diaeresis
+            artist: "Bjo\u{0308}rk".to_string(),
+            genre: Some("Électronique".to_string()),
+            year: Some(1997),
+            rating: None,
+            starred: false,
+        };
+        assert!(SearchQuery::parse("artist:bjork title:joga genre:electronique").matches_song(&song, &info));
+        assert!(SearchQuery::parse("artist:Björk").matches_song(&song, &info));
+        assert!(!SearchQuery::parse("artist:bjorn").matches_song(&song, &info));
+        assert!(SearchQuery::parse("JOGA").matches_name("Jóga (live)"));
+    }
+
+    #[test]
+    fn partial_limits() {
+        let query: SearchQuery = serde_json::from_str(r#"{"text":"blue","limits":{"songs":5}}"#).unwrap();
+        assert_eq!(SearchLimits { songs: 5, ..SearchLimits::default() }, query.limits);
+        let query: SearchQuery = serde_json::from_str(r#"{"text":"blue"}"#).unwrap();
+        assert_eq!(SearchLimits::default(), query.limits);
+    }
+}
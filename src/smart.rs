@@ -0,0 +1,523 @@
+use std::{cmp::Ordering, collections::HashMap, time::{SystemTime, UNIX_EPOCH}};
+use serde::{Deserialize, Serialize};
+
+use crate::{Item, SlibError, SongInfo};
+
+/// How deep `not` and parentheses may nest, rules come from clients and the parser recurses
+const MAX_DEPTH: usize = 64;
+/// How many comparisons a rule may have, each one deepens the expression
+const MAX_COMPARISONS: usize = 1024;
+
+/// A song field that rules can look at
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Field {
+    Title,
+    Artist,
+    Album,
+    Genre,
+    Year,
+    /// Length in seconds
+    Duration,
+    Rating,
+}
+
+impl Field {
+    fn parse(word: &str) -> Option<Field> {
+        match word.to_ascii_lowercase().as_str() {
+            "title" | "name" => Some(Field::Title),
+            "artist" => Some(Field::Artist),
+            "album" => Some(Field::Album),
+            "genre" => Some(Field::Genre),
+            "year" => Some(Field::Year),
+            "duration" | "length" => Some(Field::Duration),
+            "rating" => Some(Field::Rating),
+            _ => None,
+        }
+    }
+
+    fn is_numeric(self) -> bool {
+        matches!(self, Field::Year | Field::Duration | Field::Rating)
+    }
+
+    fn value(self, song: &Item, info: &SongInfo) -> Option<Value> {
+        match self {
+            Field::Title => Some(Value::Text(song.name.clone())),
+            Field::Artist => Some(Value::Text(info.artist.clone())),
+            Field::Album => Some(Value::Text(info.album.name.clone())),
+            Field::Genre => info.genre.clone().map(Value::Text),
+            Field::Year => info.year.map(|y| Value::Number(y as f64)),
+            Field::Duration => Some(Value::Number(info.length.as_secs_f64())),
+            Field::Rating => info.rating.map(|r| Value::Number(r as f64)),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub enum Value {
+    Text(String),
+    Number(f64),
+}
+
+impl Value {
+    fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
+            (Value::Text(a), Value::Text(b)) => Some(a.to_lowercase().cmp(&b.to_lowercase())),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// Text contains the value
+    Contains,
+}
+
+/// A filter expression over song fields
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare{field: Field, op: Op, value: Value},
+}
+
+impl Expr {
+    /// Whether a song passes the filter, songs missing the field never match
+    pub fn matches(&self, song: &Item, info: &SongInfo) -> bool {
+        match self {
+            Expr::And(a, b) => a.matches(song, info) && b.matches(song, info),
+            Expr::Or(a, b) => a.matches(song, info) || b.matches(song, info),
+            Expr::Not(e) => !e.matches(song, info),
+            Expr::Compare{field, op, value} => {
+                let Some(actual) = field.value(song, info) else { return false };
+                if let (Op::Contains, Value::Text(a), Value::Text(b)) = (op, &actual, value) {
+                    return a.to_lowercase().contains(&b.to_lowercase());
+                }
+                let Some(ordering) = actual.compare(value) else { return false };
+                match op {
+                    Op::Eq | Op::Contains => ordering == Ordering::Equal,
+                    Op::Ne => ordering != Ordering::Equal,
+                    Op::Lt => ordering == Ordering::Less,
+                    Op::Le => ordering != Ordering::Greater,
+                    Op::Gt => ordering == Ordering::Greater,
+                    Op::Ge => ordering != Ordering::Less,
+                }
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+pub enum Order {
+    Random,
+    Ascending(Field),
+    Descending(Field),
+}
+
+/// A parsed smart playlist rule such as
+/// `genre = Jazz AND year < 1970 AND rating >= 4, order by random, limit 50`
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct Rule {
+    pub filter: Option<Expr>,
+    pub order: Option<Order>,
+    pub limit: Option<usize>,
+}
+
+impl Rule {
+    pub fn parse(text: &str) -> Result<Rule, SlibError>
+    {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser{tokens, pos: 0, depth: 0, comparisons: 0};
+        let rule = parser.rule()?;
+        match parser.peek() {
+            None => Ok(rule),
+            Some(token) => Err(parser.error(&format!("unexpected {token:?}"))),
+        }
+    }
+
+    /// Pick, order and limit the songs of a library that the rule selects
+    pub fn apply(&self, library: &[(Item, SongInfo)]) -> Vec<Item>
+    {
+        let mut songs: Vec<&(Item, SongInfo)> = library.iter()
+            .filter(|(song, info)| self.filter.as_ref().is_none_or(|f| f.matches(song, info)))
+            .collect();
+
+        match self.order {
+            Some(Order::Random) => shuffle(&mut songs),
+            Some(Order::Ascending(field)) => songs.sort_by(|a, b| compare_field(field, a, b)),
+            Some(Order::Descending(field)) => songs.sort_by(|a, b| compare_field(field, b, a)),
+            None => {},
+        }
+
+        songs.into_iter()
+            .take(self.limit.unwrap_or(usize::MAX))
+            .map(|(song, _)| song.clone())
+            .collect()
+    }
+
+    /// Like `apply`, but a random order keeps the songs it picked before that still match,
+    /// in the same order, so the songs only change when the library does
+    pub fn reapply(&self, library: &[(Item, SongInfo)], current: &[Item]) -> Vec<Item>
+    {
+        if self.order != Some(Order::Random) {
+            return self.apply(library);
+        }
+        let positions: HashMap<&str, usize> = current.iter().enumerate().map(|(i, song)| (song.id.as_str(), i)).collect();
+        let (mut songs, new): (Vec<Item>, Vec<Item>) = Rule{limit: None, ..self.clone()}.apply(library)
+            .into_iter()
+            .partition(|song| positions.contains_key(song.id.as_str()));
+        songs.sort_by_key(|song| positions[song.id.as_str()]);
+        songs.extend(new);
+        songs.truncate(self.limit.unwrap_or(usize::MAX));
+        songs
+    }
+}
+
+/// Songs missing the field sort last
+fn compare_field(field: Field, a: &(Item, SongInfo), b: &(Item, SongInfo)) -> Ordering
+{
+    match (field.value(&a.0, &a.1), field.value(&b.0, &b.1)) {
+        (Some(a), Some(b)) => a.compare(&b).unwrap_or(Ordering::Equal),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+/// Fisher-Yates with a xorshift seeded from the clock, good enough for picking songs
+pub(crate) fn shuffle<T>(items: &mut [T])
+{
+    shuffle_seeded(items, random_seed());
+}
+
+pub(crate) fn random_seed() -> u64
+{
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
+
+/// The same seed always gives the same order, for paging through a shuffle
+pub(crate) fn shuffle_seeded<T>(items: &mut [T], seed: u64)
+{
+    let mut state = seed | 1;
+    for i in (1..items.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        items.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Word(String),
+    Text(String),
+    Number(f64),
+    Op(Op),
+    Open,
+    Close,
+    Comma,
+}
+
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, SlibError>
+{
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+
+    while let Some(&(at, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let token = match c {
+            '(' => { chars.next(); Token::Open },
+            ')' => { chars.next(); Token::Close },
+            ',' => { chars.next(); Token::Comma },
+            '~' => { chars.next(); Token::Op(Op::Contains) },
+            '=' => {
+                chars.next();
+                // Allow == as well
+                let _ = chars.next_if(|&(_, c)| c == '=');
+                Token::Op(Op::Eq)
+            },
+            '!' | '<' | '>' => {
+                chars.next();
+                let eq = chars.next_if(|&(_, c)| c == '=').is_some();
+                match (c, eq) {
+                    ('!', true) => Token::Op(Op::Ne),
+                    ('<', false) => Token::Op(Op::Lt),
+                    ('<', true) => Token::Op(Op::Le),
+                    ('>', false) => Token::Op(Op::Gt),
+                    ('>', true) => Token::Op(Op::Ge),
+                    _ => return Err(SlibError::InvalidRule(format!("expected != at {at}"))),
+                }
+            },
+            '"' | '\'' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, q)) if q == c => break,
+                        Some((_, ch)) => text.push(ch),
+                        None => return Err(SlibError::InvalidRule(format!("unclosed quote at {at}"))),
+                    }
+                }
+                Token::Text(text)
+            },
+            c if c.is_ascii_digit() || c == '.' => {
+                let mut number = String::new();
+                while let Some((_, ch)) = chars.next_if(|&(_, ch)| ch.is_ascii_digit() || ch == '.') {
+                    number.push(ch);
+                }
+                let number = number.parse()
+                    .map_err(|_| SlibError::InvalidRule(format!("bad number at {at}")))?;
+                Token::Number(number)
+            },
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut word = String::new();
+                while let Some((_, ch)) = chars.next_if(|&(_, ch)| ch.is_alphanumeric() || ch == '_' || ch == '-') {
+                    word.push(ch);
+                }
+                Token::Word(word)
+            },
+            c => return Err(SlibError::InvalidRule(format!("unexpected '{c}' at {at}"))),
+        };
+        tokens.push((at, token));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    /// How many `not`s and parentheses the current position is inside
+    depth: usize,
+    comparisons: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, t)| t)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|(_, t)| t.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn error(&self, message: &str) -> SlibError {
+        match self.tokens.get(self.pos) {
+            Some((at, _)) => SlibError::InvalidRule(format!("{message} at {at}")),
+            None => SlibError::InvalidRule(format!("{message} at end of rule")),
+        }
+    }
+
+    /// Consume a keyword if it is next
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found = self.at_keyword(keyword);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn rule(&mut self) -> Result<Rule, SlibError> {
+        let mut rule = Rule{filter: None, order: None, limit: None};
+
+        if !self.at_clause() {
+            rule.filter = Some(self.or()?);
+        }
+        loop {
+            while self.peek() == Some(&Token::Comma) {
+                self.pos += 1;
+            }
+            if self.keyword("order") {
+                if !self.keyword("by") {
+                    return Err(self.error("expected by"));
+                }
+                rule.order = Some(self.order()?);
+            }
+            else if self.keyword("limit") {
+                match self.next() {
+                    Some(Token::Number(n)) if n.fract() == 0.0 && n >= 0.0 => rule.limit = Some(n as usize),
+                    _ => { self.pos -= 1; return Err(self.error("expected a whole number")) },
+                }
+            }
+            else {
+                return Ok(rule);
+            }
+        }
+    }
+
+    fn at_clause(&self) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w.eq_ignore_ascii_case("order") || w.eq_ignore_ascii_case("limit"))
+    }
+
+    fn order(&mut self) -> Result<Order, SlibError> {
+        if self.keyword("random") {
+            return Ok(Order::Random);
+        }
+        let field = self.field()?;
+        if self.keyword("desc") {
+            Ok(Order::Descending(field))
+        }
+        else {
+            self.keyword("asc");
+            Ok(Order::Ascending(field))
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, SlibError> {
+        let mut expr = self.and()?;
+        while self.keyword("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, SlibError> {
+        let mut expr = self.not()?;
+        while self.keyword("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, SlibError> {
+        let nested = self.peek() == Some(&Token::Open) || self.at_keyword("not");
+        if !nested {
+            return self.compare();
+        }
+        if self.depth == MAX_DEPTH {
+            return Err(self.error("nested too deep"));
+        }
+        self.depth += 1;
+        let expr = self.nested();
+        self.depth -= 1;
+        expr
+    }
+
+    fn nested(&mut self) -> Result<Expr, SlibError> {
+        if self.keyword("not") {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.pos += 1;
+        let expr = self.or()?;
+        if self.next() != Some(Token::Close) {
+            self.pos -= 1;
+            return Err(self.error("expected )"));
+        }
+        Ok(expr)
+    }
+
+    fn at_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    fn field(&mut self) -> Result<Field, SlibError> {
+        match self.next() {
+            Some(Token::Word(w)) => match Field::parse(&w) {
+                Some(field) => Ok(field),
+                None => { self.pos -= 1; Err(self.error(&format!("unknown field {w}"))) },
+            },
+            _ => { self.pos -= 1; Err(self.error("expected a field")) },
+        }
+    }
+
+    fn compare(&mut self) -> Result<Expr, SlibError> {
+        if self.comparisons == MAX_COMPARISONS {
+            return Err(self.error("too many comparisons"));
+        }
+        self.comparisons += 1;
+        let field = self.field()?;
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            _ => { self.pos -= 1; return Err(self.error("expected a comparison")) },
+        };
+        let value = match self.next() {
+            Some(Token::Number(n)) if field.is_numeric() => Value::Number(n),
+            Some(Token::Number(n)) => Value::Text(n.to_string()),
+            Some(Token::Text(t)) | Some(Token::Word(t)) if !field.is_numeric() => Value::Text(t),
+            _ => { self.pos -= 1; return Err(self.error("expected a value for the field")) },
+        };
+        if op == Op::Contains && field.is_numeric() {
+            return Err(self.error("~ only works on text fields"));
+        }
+        Ok(Expr::Compare{field, op, value})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn song(id: &str, genre: &str, year: u16, rating: u8) -> (Item, SongInfo) {
+        (
+            Item { name: format!("Song {id}"), id: id.to_string(), image_path: String::new() },
+            SongInfo {
+                length: Duration::from_secs(200),
+                album: Item { name: "Album".to_string(), id: "a".to_string(), image_path: String::new() },
+                artist: "Artist".to_string(),
+                genre: Some(genre.to_string()),
+                year: Some(year),
+                rating: Some(rating),
+                starred: false,
+            },
+        )
+    }
+
+    #[test]
+    fn parse_and_apply() {
+        let library = vec![
+            song("1", "Jazz", 1959, 5),
+            song("2", "Jazz", 1975, 5),
+            song("3", "jazz", 1965, 4),
+            song("4", "Rock", 1960, 5),
+            song("5", "Jazz", 1961, 3),
+        ];
+
+        let rule = Rule::parse("genre = Jazz AND year < 1970 AND rating >= 4, order by year desc, limit 50").unwrap();
+        assert_eq!(vec![library[2].0.clone(), library[0].0.clone()], rule.apply(&library));
+
+        let rule = Rule::parse("NOT (genre = jazz OR rating < 4) limit 1").unwrap();
+        assert_eq!(vec![library[3].0.clone()], rule.apply(&library));
+
+        assert_eq!(3, Rule::parse("order by random, limit 3").unwrap().apply(&library).len());
+
+        // A random pick stays put until the library changes
+        let rule = Rule::parse("rating >= 4 order by random, limit 3").unwrap();
+        let picked = rule.apply(&library);
+        for _ in 0..10 {
+            assert_eq!(picked, rule.reapply(&library, &picked));
+        }
+        let dropped = picked[0].id.clone();
+        let library: Vec<_> = library.into_iter().filter(|(song, _)| song.id != dropped).collect();
+        let repicked = rule.reapply(&library, &picked);
+        assert_eq!(picked[1..], repicked[..2]);
+        assert_eq!(3, repicked.len());
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Rule::parse("mood = happy").is_err());
+        assert!(Rule::parse("year < recent").is_err());
+        assert!(Rule::parse("genre = 'Jazz").is_err());
+        assert!(Rule::parse("(genre = Jazz").is_err());
+        assert!(Rule::parse("genre = Jazz limit").is_err());
+
+        // Deep nesting is refused rather than overflowing the stack
+        assert!(Rule::parse(&format!("{}genre = Jazz", "not ".repeat(64))).is_ok());
+        assert!(Rule::parse(&format!("{}genre = Jazz", "not ".repeat(100_000))).is_err());
+        assert!(Rule::parse(&format!("{}genre = Jazz{}", "(".repeat(100_000), ")".repeat(100_000))).is_err());
+        assert!(Rule::parse(&vec!["year > 1"; 100_000].join(" and ")).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use std::{collections::VecDeque, fs, io, path::Path, time::Duration};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{subsonic, Item, SlibError, Status};
+
+/// How often `Daemon::start` saves the playback state, whether or not commands come in
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What is needed to pick playback back up after a restart
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct PlaybackState {
+    pub queue: VecDeque<Item>,
+    pub current_song: Option<Item>,
+    pub position: Duration,
+    pub volume: f32,
+}
+
+impl PlaybackState {
+    pub fn from_status(status: &Status) -> PlaybackState
+    {
+        PlaybackState {
+            queue: status.queue.clone(),
+            current_song: status.current_song.clone(),
+            position: status.position,
+            volume: status.volume,
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Option<PlaybackState>>
+    {
+        match fs::read_to_string(path) {
+            Ok(data) => Ok(Some(serde_json::from_str(&data)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()>
+    {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string(self)?)?;
+        fs::rename(tmp, path)
+    }
+}
+
+/// Hand the queue to the server with `savePlayQueue`, so another machine can pick it up
+pub fn save_play_queue(server: &subsonic::Server, state: &PlaybackState) -> Result<(), SlibError>
+{
+    let position = state.position.as_millis().to_string();
+    let mut params: Vec<(&str, &str)> = state.current_song.iter()
+        .chain(&state.queue)
+        .map(|song| ("id", song.id.as_str()))
+        .collect();
+    if let Some(current) = &state.current_song {
+        params.push(("current", &current.id));
+        params.push(("position", &position));
+    }
+    server.call("savePlayQueue", &params).map(|_| ())
+}
+
+/// Take the queue saved on the server with `getPlayQueue`, None if there isn't one.
+/// Subsonic doesn't keep the volume, so it is left at `volume`.
+pub fn fetch_play_queue(server: &subsonic::Server, volume: f32) -> Result<Option<PlaybackState>, SlibError>
+{
+    let response = match server.call("getPlayQueue", &[]) {
+        Ok(response) => response,
+        // Code 70 is not found, nothing has been saved yet
+        Err(SlibError::ServerError(70, _)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(from_subsonic(&response["playQueue"], volume))
+}
+
+fn from_subsonic(value: &Value, volume: f32) -> Option<PlaybackState>
+{
+    let mut queue: VecDeque<Item> = value["entry"].as_array()?.iter()
+        .filter_map(|entry| Some(Item {
+            name: entry["title"].as_str().unwrap_or_default().to_string(),
+            id: entry["id"].as_str()?.to_string(),
+            image_path: entry["coverArt"].as_str().unwrap_or_default().to_string(),
+        }))
+        .collect();
+
+    // Everything before the current song has already played
+    let current = value["current"].as_str()
+        .and_then(|id| queue.iter().position(|song| song.id == id));
+    let current_song = current.map(|index| {
+        queue.drain(..index);
+        queue.pop_front().unwrap()
+    });
+    Some(PlaybackState {
+        queue,
+        current_song,
+        position: Duration::from_millis(value["position"].as_u64().unwrap_or_default()),
+        volume,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn play_queue() {
+        let value: Value = serde_json::from_str(r#"{
+            "current": "2", "position": 61500,
+            "entry": [{"id": "1", "title": "One"}, {"id": "2", "title": "Two"}, {"id": "3", "title": "Three"}]
+        }"#).unwrap();
+        let state = from_subsonic(&value, 0.5).unwrap();
+        assert_eq!(Some("Two"), state.current_song.as_ref().map(|s| s.name.as_str()));
+        assert_eq!(vec!["3"], state.queue.iter().map(|s| s.id.as_str()).collect::<Vec<_>>());
+        assert_eq!(Duration::from_millis(61500), state.position);
+
+        let path = std::env::temp_dir().join(format!("slib-state-{}.json", std::process::id()));
+        assert_eq!(None, PlaybackState::load(&path).unwrap());
+        state.save(&path).unwrap();
+        assert_eq!(Some(state), PlaybackState::load(&path).unwrap());
+        fs::remove_file(path).unwrap();
+    }
+}
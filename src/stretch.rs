@@ -0,0 +1,249 @@
+use std::{collections::VecDeque, f32::consts::PI};
+
+pub const MIN_RATE: f32 = 0.5;
+pub const MAX_RATE: f32 = 3.0;
+
+/// Segments are this long, long enough to hold a couple of periods of a low voice
+const SEGMENT_MS: u32 = 40;
+/// How far either way a segment may move to line up with the one before it
+const SEARCH_MS: u32 = 10;
+
+/// Changes speed without changing pitch, by WSOLA.
+///
+/// Windowed segments are taken from the input every `hop * rate` frames and
+/// overlapped every `hop` frames of output, with each segment nudged to where
+/// it best continues the last one so the waveforms line up.
+pub struct TimeStretch {
+    channels: usize,
+    rate: f32,
+    /// Frames in a segment, twice the hop so Hann windows add up to one
+    segment: usize,
+    hop: usize,
+    search: usize,
+    window: Vec<f32>,
+    input: Vec<f32>,
+    /// The frame `input` starts at, counting from the first frame pushed
+    offset: usize,
+    /// Where the next segment would start at exactly the rate
+    next: f64,
+    /// Where the last segment started
+    previous: Option<usize>,
+    /// The second half of the last segment, still to be added under the next one
+    tail: Vec<f32>,
+    ready: VecDeque<f32>,
+    /// Silence in front of the audio, from priming the input
+    delay: usize,
+    /// Frames of output that are audio, as opposed to silence pushed after the end
+    audible: f64,
+}
+
+impl TimeStretch {
+    pub fn new(channels: usize, sample_rate: u32, rate: f32) -> TimeStretch
+    {
+        let hop = (sample_rate * SEGMENT_MS / 2000) as usize;
+        let segment = hop * 2;
+        // Periodic Hann, so overlapping at half its length sums to exactly one
+        let window = (0..segment).map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / segment as f32).cos()).collect();
+        TimeStretch {
+            channels,
+            rate: rate.clamp(MIN_RATE, MAX_RATE),
+            segment,
+            hop,
+            search: (sample_rate * SEARCH_MS / 1000) as usize,
+            window,
+            // Start half a segment early, so the first audio is at full weight instead of faded in
+            input: vec![0.0; hop * channels],
+            offset: 0,
+            next: 0.0,
+            previous: None,
+            tail: vec![0.0; hop * channels],
+            ready: VecDeque::new(),
+            delay: hop,
+            audible: 0.0,
+        }
+    }
+
+    pub fn rate(&self) -> f32
+    {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, rate: f32)
+    {
+        self.rate = rate.clamp(MIN_RATE, MAX_RATE);
+    }
+
+    /// Frames of output ready to pop
+    pub fn available(&self) -> usize
+    {
+        (self.ready.len() / self.channels).saturating_sub(self.delay)
+    }
+
+    /// Frames of input taken in that haven't come out yet, in input time
+    pub fn latency(&self) -> usize
+    {
+        // Stepping moves the next start by up to three hops, which can be past the end of the input
+        let pending = (self.offset + self.input.len() / self.channels) as f64 - self.next;
+        // The newest output lines up with half a segment before the next start
+        let ready = (self.available() + self.hop) as f64 * self.rate as f64 - self.hop as f64;
+        (pending + ready).max(0.0).round() as usize
+    }
+
+    /// Take in interleaved samples, of which the first `audio` frames are audio rather than trailing silence
+    pub fn push(&mut self, samples: &[f32], audio: usize)
+    {
+        self.input.extend_from_slice(samples);
+        self.audible += audio as f64 / self.rate as f64;
+        while self.step() {}
+    }
+
+    /// Fill `out` with what is ready, returns how many frames of it are audio
+    pub fn pop(&mut self, out: &mut [f32]) -> usize
+    {
+        // The silence from priming never comes out
+        let skip = self.delay.min(self.ready.len() / self.channels);
+        self.ready.drain(..skip * self.channels);
+        self.delay -= skip;
+
+        let frames = (out.len() / self.channels).min(self.available());
+        let mut audio = 0;
+        for frame in out.chunks_exact_mut(self.channels).take(frames) {
+            for sample in frame {
+                *sample = self.ready.pop_front().unwrap_or(0.0);
+            }
+            if self.audible > 0.0 {
+                self.audible -= 1.0;
+                audio += 1;
+            }
+        }
+        audio
+    }
+
+    /// Channel sum of an input frame
+    fn mono(&self, frame: usize) -> f32
+    {
+        let at = (frame - self.offset) * self.channels;
+        self.input[at..at + self.channels].iter().sum()
+    }
+
+    /// Overlap one more segment, returns false if more input is needed
+    fn step(&mut self) -> bool
+    {
+        let nominal = self.next.round() as usize;
+        let end = self.offset + self.input.len() / self.channels;
+        if nominal + self.search + self.segment > end {
+            return false;
+        }
+
+        // The start that best matches how the last segment would have gone on
+        let lowest = nominal.saturating_sub(self.search).max(self.offset);
+        let start = match self.previous {
+            None => nominal,
+            Some(previous) => {
+                let target = previous + self.hop;
+                let mut best = (f32::MIN, nominal);
+                for candidate in lowest..=nominal + self.search {
+                    let (mut dot, mut energy) = (0.0, 0.0);
+                    for i in (0..self.hop).step_by(2) {
+                        let x = self.mono(candidate + i);
+                        dot += x * self.mono(target + i);
+                        energy += x * x;
+                    }
+                    let score = dot / (energy.sqrt() + 1e-9);
+                    if score > best.0 {
+                        best = (score, candidate);
+                    }
+                }
+                best.1
+            },
+        };
+
+        let at = (start - self.offset) * self.channels;
+        let mut segment = self.input[at..at + self.segment * self.channels].to_vec();
+        for (i, frame) in segment.chunks_exact_mut(self.channels).enumerate() {
+            for sample in frame {
+                *sample *= self.window[i];
+            }
+        }
+        let (head, tail) = segment.split_at(self.hop * self.channels);
+        self.ready.extend(head.iter().zip(&self.tail).map(|(a, b)| a + b));
+        self.tail = tail.to_vec();
+
+        self.previous = Some(start);
+        self.next += self.hop as f64 * self.rate as f64;
+
+        // Drop what neither the next search nor the next match can reach
+        let keep = (self.next.round() as usize).saturating_sub(self.search).min(start + self.hop);
+        if keep > self.offset {
+            self.input.drain(..(keep - self.offset) * self.channels);
+            self.offset = keep;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{playback::{BufferSource, Engine, Format}, replaygain::GainInfo};
+
+    const FORMAT: Format = Format { sample_rate: 8000, channels: 1 };
+
+    fn sine(frequency: f32, frames: usize) -> Vec<f32> {
+        (0..frames).map(|i| (2.0 * PI * frequency * i as f32 / FORMAT.sample_rate as f32).sin() * 0.5).collect()
+    }
+
+    /// Estimate the pitch by counting upward zero crossings
+    fn frequency(samples: &[f32]) -> f32 {
+        let crossings = samples.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        crossings as f32 * FORMAT.sample_rate as f32 / samples.len() as f32
+    }
+
+    #[test]
+    fn speed_without_pitch() {
+        for rate in [0.5, 2.0, 3.0] {
+            let mut engine = Engine::new(FORMAT);
+            assert!(engine.set_rate(rate));
+            engine.play(Box::new(BufferSource::new(FORMAT, sine(440.0, 16000))), GainInfo::default());
+
+            let mut out = vec![0.0; 40000];
+            let audio = engine.render(&mut out);
+
+            // Two seconds of audio takes two seconds over the rate
+            let expected = 16000.0 / rate;
+            assert!((audio as f32 - expected).abs() < expected * 0.02, "{rate}: {audio}");
+            let steady = &out[1000..audio - 1000];
+            assert!((frequency(steady) - 440.0).abs() < 10.0, "{rate}: {}", frequency(steady));
+        }
+        assert!(!Engine::new(FORMAT).set_rate(4.0));
+    }
+
+    #[test]
+    fn position_at_rate() {
+        let mut engine = Engine::new(FORMAT);
+        engine.set_rate(2.0);
+        engine.play(Box::new(BufferSource::new(FORMAT, sine(440.0, 16000))), GainInfo::default());
+
+        // Half a second of listening at double speed is a second into the track
+        let mut out = vec![0.0; 4000];
+        engine.render(&mut out);
+        let position = engine.position().as_secs_f32();
+        assert!((position - 1.0).abs() < 0.05, "{position}");
+
+        // At the fastest rate stepping runs ahead of the input, in buffers that don't line up with it
+        for format in [FORMAT, Format { sample_rate: 44100, channels: 2 }] {
+            let mut engine = Engine::new(format);
+            engine.set_rate(3.0);
+            let frames = format.sample_rate as usize * 10;
+            engine.play(Box::new(BufferSource::new(format, vec![0.25; frames * format.channels as usize])), GainInfo::default());
+            let mut listened = 0;
+            for size in [333, 1000, 517, 29, 2048, 771].into_iter().cycle().take(30) {
+                let mut out = vec![0.0; size * format.channels as usize];
+                listened += engine.render(&mut out);
+                let expected = listened as f32 * 3.0 / format.sample_rate as f32;
+                let position = engine.position().as_secs_f32();
+                assert!((position - expected).abs() < 0.05, "{expected}: {position}");
+            }
+        }
+    }
+}
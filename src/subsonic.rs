@@ -0,0 +1,145 @@
+use std::{io::Read, sync::OnceLock, time::{Duration, SystemTime, UNIX_EPOCH}};
+use serde_json::Value;
+
+use crate::{config::Transcoding, SlibError};
+
+const API_VERSION: &str = "1.16.1";
+const CLIENT: &str = "slib";
+/// How long to wait for a server to take the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a server may go quiet in the middle of a request
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Every request goes through this, so a stalled server fails the call instead of hanging the daemon
+fn agent() -> &'static ureq::Agent
+{
+    static AGENT: OnceLock<ureq::Agent> = OnceLock::new();
+    AGENT.get_or_init(|| {
+        ureq::AgentBuilder::new()
+            .timeout_connect(CONNECT_TIMEOUT)
+            .timeout_read(READ_TIMEOUT)
+            .timeout_write(READ_TIMEOUT)
+            .build()
+    })
+}
+
+/// A Subsonic server and the account to use on it
+#[derive(Clone)]
+pub struct Server {
+    pub url: String,
+    pub user: String,
+    password: String,
+}
+
+impl Server {
+    pub fn new(url: impl Into<String>, user: impl Into<String>, password: impl Into<String>) -> Server
+    {
+        Server { url: url.into(), user: user.into(), password: password.into() }
+    }
+
+    /// Build the url of a REST method, authenticated with a salted token
+    pub fn rest_url(&self, method: &str, params: &[(&str, &str)]) -> String
+    {
+        let salt = format!("{:x}", SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+        let token = format!("{:x}", md5::compute(format!("{}{salt}", self.password)));
+
+        let mut url = format!(
+            "{}/rest/{method}?u={}&t={token}&s={salt}&v={API_VERSION}&c={CLIENT}&f=json",
+            self.url.trim_end_matches('/'),
+            encode(&self.user),
+        );
+        for (key, value) in params {
+            url += &format!("&{key}={}", encode(value));
+        }
+        url
+    }
+
+    /// Call a REST method and return the `subsonic-response` object
+    pub fn call(&self, method: &str, params: &[(&str, &str)]) -> Result<Value, SlibError>
+    {
+        let body = agent().get(&self.rest_url(method, params))
+            .call()
+            .map_err(|e| SlibError::Request(e.to_string()))?
+            .into_string()
+            .map_err(|e| SlibError::Request(e.to_string()))?;
+        parse_response(&body)
+    }
+
+    /// Call a REST method that returns a file, such as `getCoverArt`
+    pub fn fetch(&self, method: &str, params: &[(&str, &str)]) -> Result<Vec<u8>, SlibError>
+    {
+        let response = agent().get(&self.rest_url(method, params))
+            .call()
+            .map_err(|e| SlibError::Request(e.to_string()))?;
+
+        // Errors come back as json instead of the file
+        if response.content_type().contains("json") {
+            let body = response.into_string().map_err(|e| SlibError::Request(e.to_string()))?;
+            parse_response(&body)?;
+            return Err(SlibError::Request(format!("{method} did not return a file")));
+        }
+
+        let mut bytes = Vec::new();
+        response.into_reader().read_to_end(&mut bytes).map_err(SlibError::Io)?;
+        Ok(bytes)
+    }
+
+    /// Where to stream a song from, transcoded by the server
+    pub fn stream_url(&self, id: &str, quality: &Transcoding) -> String
+    {
+        let params = quality.params();
+        let mut all = vec![("id", id)];
+        all.extend(params.iter().map(|(key, value)| (*key, value.as_str())));
+        self.rest_url("stream", &all)
+    }
+
+    /// Where to download a song from for offline playback. `download` only
+    /// sends the original file, so other qualities come from `stream`.
+    pub fn download_url(&self, id: &str, quality: &Transcoding) -> String
+    {
+        if quality.is_original() {
+            self.rest_url("download", &[("id", id)])
+        }
+        else {
+            self.stream_url(id, quality)
+        }
+    }
+
+    /// Check that the server is up and accepts our credentials
+    pub fn ping(&self) -> Result<(), SlibError>
+    {
+        self.call("ping", &[]).map(|_| ())
+    }
+}
+
+/// Unwrap the `subsonic-response` object, turning failures into errors
+fn parse_response(body: &str) -> Result<Value, SlibError>
+{
+    let mut json: Value = serde_json::from_str(body).map_err(|e| SlibError::Request(e.to_string()))?;
+    let response = json.get_mut("subsonic-response").map(Value::take)
+        .ok_or_else(|| SlibError::Request(String::from("missing subsonic-response")))?;
+
+    if response["status"] == "ok" {
+        Ok(response)
+    }
+    else {
+        let error = &response["error"];
+        Err(SlibError::ServerError(
+            error["code"].as_u64().unwrap_or(0) as u32,
+            error["message"].as_str().unwrap_or_default().to_string(),
+        ))
+    }
+}
+
+/// Percent-encode a query parameter
+fn encode(text: &str) -> String
+{
+    let mut out = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(byte as char),
+            _ => out += &format!("%{byte:02X}"),
+        }
+    }
+    out
+}